@@ -0,0 +1,57 @@
+#![feature(test)]
+
+extern crate test;
+
+use image::{DynamicImage, ImageBuffer, Rgba};
+use stepth::{MaskImage, MASK_FALSE, MASK_TRUE};
+use test::Bencher;
+
+const WIDTH: u32 = 7744;
+const HEIGHT: u32 = 5164;
+
+fn small_mask_photo() -> MaskImage {
+    let image = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        Rgba([(x * 7) as u8, (y * 11) as u8, ((x + y) * 3) as u8, u8::MAX])
+    });
+    let mut res = MaskImage::from_image(DynamicImage::ImageRgba8(image));
+    res.mask = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        if (3000..3400).contains(&x) && (2000..2300).contains(&y) {
+            MASK_TRUE
+        } else {
+            MASK_FALSE
+        }
+    });
+    res
+}
+
+fn baseline_adjust(img: &mut MaskImage, adjust: impl Fn(DynamicImage) -> DynamicImage) {
+    let image_to_mod = adjust(DynamicImage::ImageRgba8(img.image.clone()));
+    img.image_replace(
+        &MaskImage::from_image(image_to_mod),
+        disage::Position::new(0, 0),
+    );
+}
+
+#[bench]
+fn image_brightness_small_mask_40mp(b: &mut Bencher) {
+    let mut img = small_mask_photo();
+    b.iter(|| img.image_brightness(10));
+}
+
+#[bench]
+fn baseline_brightness_small_mask_40mp(b: &mut Bencher) {
+    let mut img = small_mask_photo();
+    b.iter(|| baseline_adjust(&mut img, |i| i.brighten(10)));
+}
+
+#[bench]
+fn image_blur_small_mask_40mp(b: &mut Bencher) {
+    let mut img = small_mask_photo();
+    b.iter(|| img.image_blur(4));
+}
+
+#[bench]
+fn baseline_blur_small_mask_40mp(b: &mut Bencher) {
+    let mut img = small_mask_photo();
+    b.iter(|| baseline_adjust(&mut img, |i| i.blur(4.0)));
+}
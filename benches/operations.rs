@@ -0,0 +1,54 @@
+#![feature(test)]
+
+extern crate test;
+
+use image::{ImageBuffer, Rgb};
+use test::Bencher;
+
+const WIDTH: u32 = 6000;
+const HEIGHT: u32 = 4000;
+
+type Frame = ImageBuffer<Rgb<u16>, Vec<u16>>;
+
+fn frame_pair() -> (Frame, Frame) {
+    let first = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        Rgb([(x * 7) as u16, (y * 11) as u16, ((x + y) * 3) as u16])
+    });
+    let second = ImageBuffer::from_fn(WIDTH, HEIGHT, |x, y| {
+        Rgb([(x * 9) as u16, (y * 13) as u16, ((x + y) * 5) as u16])
+    });
+    (first, second)
+}
+
+fn baseline_normalize_rgb16(img1: &Frame, img2: &Frame) -> Frame {
+    let mean = |img: &Frame| {
+        let mut rgb = [0f64; 3];
+        img.pixels().for_each(|f| {
+            for (s, v) in rgb.iter_mut().zip(f.0.iter()) {
+                *s += *v as f64;
+            }
+        });
+        rgb.map(|s| s / img.len() as f64)
+    };
+    let (rgb1, rgb2) = (mean(img1), mean(img2));
+    let diff = [rgb2[0] / rgb1[0], rgb2[1] / rgb1[1], rgb2[2] / rgb1[2]];
+    let mut res = img1.clone();
+    res.pixels_mut().for_each(|f| {
+        for (v, d) in f.0.iter_mut().zip(diff.iter()) {
+            *v = (*v as f64 * d) as u16;
+        }
+    });
+    res
+}
+
+#[bench]
+fn normalize_brightness_rgb16_24mp(b: &mut Bencher) {
+    let (first, second) = frame_pair();
+    b.iter(|| stepth::operations::normalize_brightness_rgb16(&first, &second, 0.0));
+}
+
+#[bench]
+fn baseline_normalize_rgb16_24mp(b: &mut Bencher) {
+    let (first, second) = frame_pair();
+    b.iter(|| baseline_normalize_rgb16(&first, &second));
+}
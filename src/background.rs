@@ -0,0 +1,65 @@
+use crate::depth_image::DepthImage;
+use crate::mask_image::{BlendMode, MaskImage};
+use crate::operations;
+use image::{imageops, DynamicImage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundFit {
+    Stretch,
+    Cover,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BackgroundOptions {
+    pub refine_radius: u32,
+    pub feather_radius: f32,
+    pub fit: BackgroundFit,
+    pub match_brightness: Option<f64>,
+}
+
+impl Default for BackgroundOptions {
+    fn default() -> Self {
+        BackgroundOptions {
+            refine_radius: 2,
+            feather_radius: 3.0,
+            fit: BackgroundFit::Cover,
+            match_brightness: Some(0.05),
+        }
+    }
+}
+
+impl DepthImage {
+    pub fn replace_background(&mut self, new_bg: DynamicImage, options: BackgroundOptions) {
+        let mut background = self.select_background();
+        // opening then closing drops speckles and fills pinholes in the selection
+        background.mask_erode(options.refine_radius);
+        background.mask_dilate(options.refine_radius);
+        background.mask_dilate(options.refine_radius);
+        background.mask_erode(options.refine_radius);
+        background.mask_feather(options.feather_radius);
+        let (width, height) = (self.width(), self.height());
+        let new_bg = match options.fit {
+            BackgroundFit::Stretch => new_bg.resize_exact(width, height, imageops::Gaussian),
+            BackgroundFit::Cover => new_bg.resize_to_fill(width, height, imageops::Gaussian),
+        };
+        // only the replaced region sets the target, a kept foreground shouldn't skew it
+        let new_bg = match options.match_brightness {
+            Some(percent) => operations::normalize_brightness_masked(
+                &MaskImage::from_image(new_bg),
+                &background,
+                percent,
+            ),
+            None => new_bg.to_rgba8(),
+        };
+        let mut res = MaskImage::from_image(self.image());
+        res.composite(
+            &MaskImage {
+                image: new_bg,
+                mask: background.mask,
+            },
+            disage::Position::new(0, 0),
+            BlendMode::Normal,
+        );
+        self.image = res.image;
+    }
+}
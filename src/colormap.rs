@@ -0,0 +1,135 @@
+use crate::depth_image::DepthImage;
+use image::{DynamicImage, ImageBuffer, Rgb};
+
+const VIRIDIS: [u32; 9] = [
+    0x440154, 0x472d7b, 0x3b528b, 0x2c728e, 0x21918c, 0x28ae80, 0x5ec962, 0xaddc30, 0xfde725,
+];
+const MAGMA: [u32; 9] = [
+    0x000004, 0x1c1044, 0x4f127b, 0x812581, 0xb5367a, 0xe55964, 0xfb8761, 0xfec287, 0xfcfdbf,
+];
+const INFERNO: [u32; 9] = [
+    0x000004, 0x1f0c48, 0x550f6d, 0x88226a, 0xba3655, 0xe35933, 0xf98e09, 0xf9cb35, 0xfcffa4,
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Colormap {
+    Turbo,
+    Viridis,
+    Magma,
+    Jet,
+    Inferno,
+    Custom(Vec<(f32, Rgb<u8>)>),
+}
+
+fn hex_stops(hex: &[u32]) -> Vec<(f32, Rgb<u8>)> {
+    hex.iter()
+        .enumerate()
+        .map(|(i, h)| {
+            let [_, r, g, b] = h.to_be_bytes();
+            (i as f32 / (hex.len() - 1) as f32, Rgb([r, g, b]))
+        })
+        .collect()
+}
+
+fn gradient(stops: &[(f32, Rgb<u8>)], t: f32) -> Rgb<u8> {
+    let first = match stops.first() {
+        Some(first) => first,
+        None => return Rgb([0; 3]),
+    };
+    if t <= first.0 {
+        return first.1;
+    }
+    for pair in stops.windows(2) {
+        let ((from, c1), (to, c2)) = (pair[0], pair[1]);
+        if t <= to {
+            let k = if to > from {
+                (t - from) / (to - from)
+            } else {
+                1.0
+            };
+            let lerp = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * k).round() as u8;
+            return Rgb([
+                lerp(c1.0[0], c2.0[0]),
+                lerp(c1.0[1], c2.0[1]),
+                lerp(c1.0[2], c2.0[2]),
+            ]);
+        }
+    }
+    stops.last().unwrap().1
+}
+
+impl Colormap {
+    pub fn color(&self, t: f32) -> Rgb<u8> {
+        let t = t.clamp(0.0, 1.0);
+        let to_u8 = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+        match self {
+            Colormap::Turbo => {
+                // polynomial fit of the turbo colormap
+                let r = 0.135_721_38
+                    + t * (4.615_392_6
+                        + t * (-42.660_32 + t * (132.131_08 + t * (-152.942_39 + t * 59.286_38))));
+                let g = 0.091_402_61
+                    + t * (2.194_188_4
+                        + t * (4.842_966_6
+                            + t * (-14.185_033 + t * (4.277_298_5 + t * 2.829_566))));
+                let b = 0.106_673_3
+                    + t * (12.641_946
+                        + t * (-60.582_05 + t * (110.362_77 + t * (-89.903_11 + t * 27.348_25))));
+                Rgb([to_u8(r), to_u8(g), to_u8(b)])
+            }
+            Colormap::Jet => Rgb([
+                to_u8(1.5 - (4.0 * t - 3.0).abs()),
+                to_u8(1.5 - (4.0 * t - 2.0).abs()),
+                to_u8(1.5 - (4.0 * t - 1.0).abs()),
+            ]),
+            Colormap::Viridis => gradient(&hex_stops(&VIRIDIS), t),
+            Colormap::Magma => gradient(&hex_stops(&MAGMA), t),
+            Colormap::Inferno => gradient(&hex_stops(&INFERNO), t),
+            Colormap::Custom(stops) => gradient(stops, t),
+        }
+    }
+
+    fn lookup_table(&self) -> Vec<Rgb<u8>> {
+        (0..=u8::MAX)
+            .map(|v| self.color(v as f32 / u8::MAX as f32))
+            .collect()
+    }
+}
+
+impl DepthImage {
+    pub fn depth_colorized(
+        &self,
+        colormap: &Colormap,
+        range: Option<(u8, u8)>,
+        legend: bool,
+    ) -> DynamicImage {
+        let (from, to) = range.unwrap_or((u8::MIN, u8::MAX));
+        let span = (to as f32 - from as f32).max(1.0);
+        let table = colormap.lookup_table();
+        let color_of = |d: u8| {
+            let t = (d.clamp(from, to.max(from)) as f32 - from as f32) / span;
+            table[(t * u8::MAX as f32).round() as usize]
+        };
+        let mut res = ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
+            color_of(self.depth.get_pixel(x, y).0[0])
+        });
+        if legend {
+            let (width, height) = (self.width(), self.height());
+            let bar_width = (width / 32).max(8).min(width);
+            let margin = (height / 10).min(bar_width);
+            let bar_height = height.saturating_sub(2 * margin);
+            let left = width.saturating_sub(bar_width + margin);
+            for y in 0..bar_height {
+                // highest values on top, matching the usual colorbar orientation
+                let t = 1.0 - y as f32 / (bar_height.max(2) - 1) as f32;
+                let color = colormap.color(t);
+                for x in 0..bar_width {
+                    let border = x == 0 || x + 1 == bar_width || y == 0 || y + 1 == bar_height;
+                    let pixel = if border { Rgb([0; 3]) } else { color };
+                    res.put_pixel(left + x, margin + y, pixel);
+                }
+            }
+        }
+        DynamicImage::ImageRgb8(res)
+    }
+}
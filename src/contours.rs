@@ -0,0 +1,259 @@
+use crate::mask_image::{MaskImage, MASK_TRUE};
+use std::collections::HashMap;
+
+pub type Point = (f32, f32);
+
+type Crack = ((i64, i64), (i64, i64), (i64, i64));
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Contour {
+    pub outer: Vec<Point>,
+    pub holes: Vec<Vec<Point>>,
+}
+
+fn signed_area(ring: &[Point]) -> f32 {
+    ring.iter()
+        .zip(ring.iter().cycle().skip(1))
+        .map(|(a, b)| a.0 * b.1 - b.0 * a.1)
+        .sum::<f32>()
+        / 2.0
+}
+
+fn contains(ring: &[Point], point: Point) -> bool {
+    let mut inside = false;
+    for (a, b) in ring.iter().zip(ring.iter().cycle().skip(1)) {
+        if (a.1 > point.1) != (b.1 > point.1)
+            && point.0 < (b.0 - a.0) * (point.1 - a.1) / (b.1 - a.1) + a.0
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+fn segment_distance(p: Point, a: Point, b: Point) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let length = dx * dx + dy * dy;
+    if length == 0.0 {
+        return ((p.0 - a.0).powi(2) + (p.1 - a.1).powi(2)).sqrt();
+    }
+    (dy * p.0 - dx * p.1 + b.0 * a.1 - b.1 * a.0).abs() / length.sqrt()
+}
+
+fn douglas_peucker(points: &[Point], epsilon: f32) -> Vec<Point> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+    let (first, last) = (points[0], points[points.len() - 1]);
+    let (index, distance) = points[1..points.len() - 1]
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (i + 1, segment_distance(*p, first, last)))
+        .fold((0, -1.0), |best, v| if v.1 > best.1 { v } else { best });
+    if distance <= epsilon {
+        return vec![first, last];
+    }
+    let mut res = douglas_peucker(&points[..=index], epsilon);
+    res.pop();
+    res.extend(douglas_peucker(&points[index..], epsilon));
+    res
+}
+
+fn simplify_ring(ring: &[Point], epsilon: f32) -> Vec<Point> {
+    if ring.len() < 4 {
+        return ring.to_vec();
+    }
+    // a closed ring has no natural endpoints, so split it at the vertex farthest from the first
+    let far = (1..ring.len())
+        .max_by(|i, j| {
+            let d = |k: usize| (ring[k].0 - ring[0].0).powi(2) + (ring[k].1 - ring[0].1).powi(2);
+            d(*i).partial_cmp(&d(*j)).unwrap()
+        })
+        .unwrap();
+    let mut closed = ring.to_vec();
+    closed.push(ring[0]);
+    let mut res = douglas_peucker(&closed[..=far], epsilon);
+    res.pop();
+    res.extend(douglas_peucker(&closed[far..], epsilon));
+    res.pop();
+    // the split vertex is always kept above, drop it when it is redundant
+    if res.len() > 3 && segment_distance(res[0], res[res.len() - 1], res[1]) <= epsilon {
+        res.remove(0);
+    }
+    if res.len() < 3 {
+        return ring.to_vec();
+    }
+    res
+}
+
+impl Contour {
+    pub fn simplify(&self, epsilon: f32) -> Contour {
+        Contour {
+            outer: simplify_ring(&self.outer, epsilon),
+            holes: self
+                .holes
+                .iter()
+                .map(|h| simplify_ring(h, epsilon))
+                .collect(),
+        }
+    }
+}
+
+impl MaskImage {
+    pub fn contours(&self) -> Vec<Contour> {
+        let (width, height) = (self.width() as i64, self.height() as i64);
+        let inside = |x: i64, y: i64| {
+            x >= 0
+                && y >= 0
+                && x < width
+                && y < height
+                && *self.mask.get_pixel(x as u32, y as u32) == MASK_TRUE
+        };
+        // pixel cracks between inside and outside, walked clockwise around every inside pixel
+        let mut edges: Vec<Crack> = Vec::new();
+        for y in 0..height {
+            for x in 0..width {
+                if !inside(x, y) {
+                    continue;
+                }
+                if !inside(x, y - 1) {
+                    edges.push(((x, y), (x + 1, y), (x, y)));
+                }
+                if !inside(x + 1, y) {
+                    edges.push(((x + 1, y), (x + 1, y + 1), (x, y)));
+                }
+                if !inside(x, y + 1) {
+                    edges.push(((x + 1, y + 1), (x, y + 1), (x, y)));
+                }
+                if !inside(x - 1, y) {
+                    edges.push(((x, y + 1), (x, y), (x, y)));
+                }
+            }
+        }
+        let mut outgoing: HashMap<(i64, i64), Vec<usize>> = HashMap::new();
+        edges.iter().enumerate().for_each(|(i, e)| {
+            outgoing.entry(e.0).or_default().push(i);
+        });
+        let mut used = vec![false; edges.len()];
+        let mut rings: Vec<(Vec<Point>, Point)> = Vec::new();
+        for start in 0..edges.len() {
+            if used[start] {
+                continue;
+            }
+            let (_, _, pixel) = edges[start];
+            let mut ring = Vec::new();
+            let mut current = start;
+            loop {
+                used[current] = true;
+                let (from, to, _) = edges[current];
+                ring.push((from.0 as f32, from.1 as f32));
+                let direction = (to.0 - from.0, to.1 - from.1);
+                // prefer turning right so diagonal neighbours stay separate regions
+                let candidates = [
+                    (-direction.1, direction.0),
+                    direction,
+                    (direction.1, -direction.0),
+                ];
+                let next = outgoing.get(&to).and_then(|out| {
+                    candidates.iter().find_map(|c| {
+                        out.iter().copied().find(|i| {
+                            !used[*i] && (edges[*i].1 .0 - to.0, edges[*i].1 .1 - to.1) == *c
+                        })
+                    })
+                });
+                match next {
+                    Some(next) => current = next,
+                    None => break,
+                }
+            }
+            // merge runs of collinear cracks into single segments
+            let ring = simplify_ring(&ring, 0.0);
+            rings.push((ring, (pixel.0 as f32 + 0.5, pixel.1 as f32 + 0.5)));
+        }
+        let (outers, holes): (Vec<_>, Vec<_>) =
+            rings.into_iter().partition(|(r, _)| signed_area(r) > 0.0);
+        let mut res: Vec<Contour> = outers
+            .iter()
+            .map(|(r, _)| Contour {
+                outer: r.clone(),
+                holes: Vec::new(),
+            })
+            .collect();
+        for (hole, pixel) in holes {
+            let owner = res
+                .iter_mut()
+                .filter(|c| contains(&c.outer, pixel))
+                .min_by(|a, b| {
+                    signed_area(&a.outer)
+                        .partial_cmp(&signed_area(&b.outer))
+                        .unwrap()
+                });
+            if let Some(owner) = owner {
+                owner.holes.push(hole);
+            }
+        }
+        res
+    }
+}
+
+fn ring_path(ring: &[Point]) -> String {
+    let mut path = String::new();
+    for (i, p) in ring.iter().enumerate() {
+        path.push_str(&format!(
+            "{}{} {} ",
+            if i == 0 { "M" } else { "L" },
+            p.0,
+            p.1
+        ));
+    }
+    path.push('Z');
+    path
+}
+
+pub fn to_svg(contours: &[Contour], dimensions: disage::Dimensions) -> String {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+        dimensions.width, dimensions.height
+    );
+    for contour in contours {
+        let rings: Vec<String> = std::iter::once(&contour.outer)
+            .chain(contour.holes.iter())
+            .map(|r| ring_path(r))
+            .collect();
+        svg.push_str(&format!(
+            "  <path d=\"{}\" fill-rule=\"evenodd\"/>\n",
+            rings.join(" ")
+        ));
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn ring_coordinates(ring: &[Point]) -> String {
+    let points: Vec<String> = ring
+        .iter()
+        .chain(ring.first())
+        .map(|p| format!("[{},{}]", p.0, p.1))
+        .collect();
+    format!("[{}]", points.join(","))
+}
+
+pub fn to_geojson(contours: &[Contour]) -> String {
+    let features: Vec<String> = contours
+        .iter()
+        .map(|c| {
+            let rings: Vec<String> = std::iter::once(&c.outer)
+                .chain(c.holes.iter())
+                .map(|r| ring_coordinates(r))
+                .collect();
+            format!(
+                "{{\"type\":\"Feature\",\"properties\":{{}},\"geometry\":{{\"type\":\"Polygon\",\"coordinates\":[{}]}}}}",
+                rings.join(",")
+            )
+        })
+        .collect();
+    format!(
+        "{{\"type\":\"FeatureCollection\",\"features\":[{}]}}",
+        features.join(",")
+    )
+}
@@ -1,7 +1,39 @@
+use crate::progress::Progress;
 use crate::{helpers, mask_image::*};
 use image::{imageops, DynamicImage, ImageBuffer, Luma};
 use rayon::prelude::*;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const OBJECT_DEPTH_STEP: u8 = 4;
+const PROGRESS_STEP: usize = 1024;
+
+type DepthRange = (Option<u8>, Option<u8>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Connectivity {
+    Four,
+    Eight,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthHighlight {
+    pub brightness: (f32, f32),
+    pub tint: Option<image::Rgb<u8>>,
+    pub opacity: f32,
+    pub side_by_side: bool,
+}
+
+impl Default for DepthHighlight {
+    fn default() -> Self {
+        DepthHighlight {
+            brightness: (0.0, 2.0),
+            tint: None,
+            opacity: 0.5,
+            side_by_side: false,
+        }
+    }
+}
 
 #[derive(Clone, Default)]
 pub struct DepthImage {
@@ -49,16 +81,28 @@ impl DepthImage {
     }
 
     pub fn highlight_depth(&self) -> DynamicImage {
+        self.highlight_depth_with(&DepthHighlight::default())
+    }
+
+    pub fn highlight_depth_with(&self, options: &DepthHighlight) -> DynamicImage {
+        let (low, high) = options.brightness;
         let mut res = self.image.clone();
         res.pixels_mut()
             .zip(self.depth.pixels())
             .for_each(|(p, d)| {
-                let multiplier = d.0[0] as f32 / 255.0 * 2.0;
-                let adjust = |v: u8| (v as f32 * multiplier).max(0.0).min(255.0) as u8;
+                let level = d.0[0] as f32 / 255.0;
+                let multiplier = low + (high - low) * level;
+                let adjust = |v: u8| (v as f32 * multiplier).clamp(0.0, 255.0) as u8;
                 p.0[0] = adjust(p.0[0]);
                 p.0[1] = adjust(p.0[1]);
                 p.0[2] = adjust(p.0[2]);
+                if let Some(tint) = options.tint {
+                    helpers::blend_tint(p, tint, options.opacity * level);
+                }
             });
+        if options.side_by_side {
+            return helpers::side_by_side(&self.image, &res);
+        }
         DynamicImage::ImageRgba8(res)
     }
 
@@ -77,6 +121,15 @@ impl DepthImage {
         &mut self,
         add_path: &str,
         precision: [u8; 3],
+    ) -> Result<(), std::io::Error> {
+        self.open_depth_from_additional_with(add_path, precision, Progress::default())
+    }
+
+    pub fn open_depth_from_additional_with(
+        &mut self,
+        add_path: &str,
+        precision: [u8; 3],
+        progress: Progress,
     ) -> Result<(), std::io::Error> {
         let add_image = image::open(add_path);
         if add_image.is_err() {
@@ -85,7 +138,7 @@ impl DepthImage {
                 "Failed to open image",
             ));
         }
-        self.load_depth_from_additional(add_image.unwrap(), precision)
+        self.load_depth_from_additional_with(add_image.unwrap(), precision, progress)
     }
 
     pub fn load_depth_from_additional(
@@ -93,6 +146,16 @@ impl DepthImage {
         add_image: image::DynamicImage,
         precision: [u8; 3],
     ) -> Result<(), std::io::Error> {
+        self.load_depth_from_additional_with(add_image, precision, Progress::default())
+    }
+
+    pub fn load_depth_from_additional_with(
+        &mut self,
+        add_image: image::DynamicImage,
+        precision: [u8; 3],
+        progress: Progress,
+    ) -> Result<(), std::io::Error> {
+        progress.check()?;
         let add_image = add_image.to_rgb8();
         let add_array = disage::converters::pixels_to_array(
             &disage::converters::raw_rgb(&add_image),
@@ -109,18 +172,31 @@ impl DepthImage {
         );
         let mut pixels: Vec<disage::DiscretePixel<&mut [u8; 3]>> = discr_main.pixels_mut();
         let chunk_size = pixels.len() / 8;
-        pixels.par_chunks_mut(chunk_size).for_each(|v| {
-            v.iter_mut().for_each(|p| {
-                let middle = disage::Position::new(
-                    (p.position.x + p.size.width) / 2,
-                    (p.position.y + p.size.height) / 2,
+        let total = pixels.len() as u64;
+        let done = AtomicU64::new(0);
+        progress.check()?;
+        progress.report(0, total);
+        pixels.par_chunks_mut(chunk_size).try_for_each(|v| {
+            v.chunks_mut(PROGRESS_STEP).try_for_each(|batch| {
+                progress.check()?;
+                batch.iter_mut().for_each(|p| {
+                    let middle = disage::Position::new(
+                        (p.position.x + p.size.width) / 2,
+                        (p.position.y + p.size.height) / 2,
+                    );
+                    let (d, _) =
+                        helpers::distance_dot_array(p.value, &add_array, middle, 255, precision)
+                            .unwrap_or((u32::MIN, disage::Position::new(0, 0)));
+                    *p.value = [d as u8; 3]
+                });
+                let batch_len = batch.len() as u64;
+                progress.report(
+                    done.fetch_add(batch_len, Ordering::Relaxed) + batch_len,
+                    total,
                 );
-                let (d, _) =
-                    helpers::distance_dot_array(p.value, &add_array, middle, 255, precision)
-                        .unwrap_or((u32::MIN, disage::Position::new(0, 0)));
-                *p.value = [d as u8; 3]
+                Ok::<(), std::io::Error>(())
             })
-        });
+        })?;
         let max = pixels.iter().max_by_key(|p| p.value[0]).unwrap().value[0];
         pixels.par_chunks_mut(chunk_size).for_each(|v| {
             v.iter_mut().for_each(|p| {
@@ -152,6 +228,40 @@ impl DepthImage {
             .to_luma8();
     }
 
+    pub fn crop(&self, rect: Rect) -> DepthImage {
+        let rect = rect.clamp_to(self.dimensions());
+        let (x, y) = rect.position.tuplexy();
+        let (width, height) = (rect.size.width, rect.size.height);
+        DepthImage {
+            image: imageops::crop_imm(&self.image, x, y, width, height).to_image(),
+            depth: imageops::crop_imm(&self.depth, x, y, width, height).to_image(),
+        }
+    }
+
+    pub fn composite_depth(
+        &mut self,
+        other: &DepthImage,
+        offset: disage::Position,
+        depth_offset: i16,
+    ) {
+        let (start_x, start_y) = offset.tuplexy();
+        for y in start_y..(start_y.saturating_add(other.height())).min(self.height()) {
+            for x in start_x..(start_x.saturating_add(other.width())).min(self.width()) {
+                let (other_x, other_y) = (x - start_x, y - start_y);
+                let pixel = other.image.get_pixel(other_x, other_y);
+                if pixel.0[3] == 0 {
+                    continue;
+                }
+                let depth = (other.depth.get_pixel(other_x, other_y).0[0] as i16 + depth_offset)
+                    .clamp(u8::MIN as i16, u8::MAX as i16) as u8;
+                if depth < self.depth.get_pixel(x, y).0[0] {
+                    self.image.put_pixel(x, y, *pixel);
+                    self.depth.put_pixel(x, y, Luma([depth]));
+                }
+            }
+        }
+    }
+
     pub fn dimensions(&self) -> disage::Dimensions {
         disage::Dimensions {
             width: self.width(),
@@ -159,16 +269,32 @@ impl DepthImage {
         }
     }
 
-    pub fn depth_split(&self, zones: u8) -> Vec<(Option<u8>, Option<u8>)> {
+    pub fn depth_split(&self, zones: u8) -> Vec<DepthRange> {
+        self.depth_split_with(zones, Progress::default()).unwrap()
+    }
+
+    pub fn depth_split_with(
+        &self,
+        zones: u8,
+        progress: Progress,
+    ) -> Result<Vec<DepthRange>, std::io::Error> {
         if zones < 2 {
-            return vec![(None, None)];
+            return Ok(vec![(None, None)]);
         }
-        fn inner(array: &[u8], prev_centers: Vec<u8>) -> Vec<(Option<u8>, Option<u8>)> {
+        fn inner(
+            array: &[u8],
+            prev_centers: Vec<u8>,
+            progress: Progress,
+            pass: u64,
+        ) -> Result<Vec<DepthRange>, std::io::Error> {
             let mut clusters = HashMap::new();
             prev_centers.iter().for_each(|i| {
                 clusters.insert(i.clone(), Vec::new());
             });
-            array.iter().for_each(|item| {
+            for (i, item) in array.iter().enumerate() {
+                if i % PROGRESS_STEP == 0 {
+                    progress.check()?;
+                }
                 let closest_centroid = prev_centers
                     .iter()
                     .min_by(|x, y| {
@@ -181,13 +307,16 @@ impl DepthImage {
                     .get_mut(closest_centroid)
                     .unwrap()
                     .push(item.clone());
-            });
+            }
+            // a center nothing is closest to has no range, so it's dropped
             let mut new_centroids = clusters
                 .iter()
-                .map(|(_, v)| (v.iter().map(|v| *v as usize).sum::<usize>() / v.len().max(1)) as u8)
+                .filter(|(_, v)| !v.is_empty())
+                .map(|(_, v)| (v.iter().map(|v| *v as usize).sum::<usize>() / v.len()) as u8)
                 .collect::<Vec<u8>>();
             new_centroids.sort();
-            let mut centroids_didnt_change = true;
+            new_centroids.dedup();
+            let mut centroids_didnt_change = prev_centers.len() == new_centroids.len();
             for (new, prev) in prev_centers.iter().zip(new_centroids.iter()) {
                 if new != prev {
                     centroids_didnt_change = false;
@@ -195,7 +324,8 @@ impl DepthImage {
                 }
             }
             if centroids_didnt_change {
-                return new_centroids
+                progress.report(pass + 1, pass + 1);
+                return Ok(new_centroids
                     .iter()
                     .map(|c| {
                         let v = clusters.get(c).unwrap();
@@ -204,29 +334,94 @@ impl DepthImage {
                             Some(*v.iter().max().unwrap()),
                         )
                     })
-                    .collect();
+                    .collect());
             } else {
-                return inner(array, new_centroids);
+                // the number of passes isn't known up front, so always leave one pass to go
+                progress.report(pass + 1, pass + 2);
+                return inner(array, new_centroids, progress, pass + 1);
             }
         }
         let img_min = self.depth.as_raw().iter().min().unwrap().clone();
         let img_max = self.depth.as_raw().iter().max().unwrap().clone();
-        let init_centers = (img_min..img_max)
-            .step_by(((img_max - img_min) / (zones - 1)) as usize - 1)
+        let (span, zones) = ((img_max - img_min) as usize, zones as usize);
+        // too few distinct depths to seed a separate center for every zone
+        if span < zones - 1 {
+            return Ok(vec![(None, None)]);
+        }
+        let init_centers = (0..zones)
+            .map(|i| img_min + (span * i / (zones - 1)) as u8)
             .collect();
-        inner(self.depth.as_raw(), init_centers)
+        inner(self.depth.as_raw(), init_centers, progress, 0)
     }
 
-    pub fn select_foreground(&mut self) -> MaskImage {
+    pub fn select_foreground(&self) -> MaskImage {
         let (from, to) = self.depth_split(2)[0];
         self.slice(from, to)
     }
 
+    pub fn select_background(&self) -> MaskImage {
+        let (from, to) = *self.depth_split(2).last().unwrap();
+        self.slice(from, to)
+    }
+
+    pub fn select_zone(&self, index: usize, zones: u8) -> Option<MaskImage> {
+        if index >= zones.max(1) as usize {
+            return None;
+        }
+        let (from, to) = *self.depth_split(zones).get(index)?;
+        Some(self.slice(from, to))
+    }
+
+    pub fn select_nearest_object(&self, point: disage::Position) -> MaskImage {
+        let depth = &self.depth;
+        let connectivity = Connectivity::Eight;
+        let mask = helpers::flood_fill(self.dimensions(), point, connectivity, |from, to| {
+            let (from, to) = (
+                depth.get_pixel(from.x, from.y).0[0],
+                depth.get_pixel(to.x, to.y).0[0],
+            );
+            (from as i32 - to as i32).abs() <= OBJECT_DEPTH_STEP as i32
+        });
+        MaskImage {
+            image: self.image.clone(),
+            mask,
+        }
+    }
+
+    pub fn select_region(
+        &self,
+        seed: disage::Position,
+        depth_tolerance: u8,
+        color_tolerance: u8,
+        connectivity: Connectivity,
+    ) -> MaskImage {
+        if seed.x >= self.width() || seed.y >= self.height() {
+            return MaskImage {
+                image: self.image.clone(),
+                mask: ImageBuffer::from_pixel(self.width(), self.height(), MASK_FALSE),
+            };
+        }
+        let seed_depth = self.depth.get_pixel(seed.x, seed.y).0[0];
+        let seed_color = self.image.get_pixel(seed.x, seed.y).0;
+        let mask = helpers::flood_fill(self.dimensions(), seed, connectivity, |_, to| {
+            let depth = self.depth.get_pixel(to.x, to.y).0[0];
+            let color = self.image.get_pixel(to.x, to.y).0;
+            (depth as i32 - seed_depth as i32).abs() <= depth_tolerance as i32
+                && (0..3).all(|i| {
+                    (color[i] as i32 - seed_color[i] as i32).abs() <= color_tolerance as i32
+                })
+        });
+        MaskImage {
+            image: self.image.clone(),
+            mask,
+        }
+    }
+
     pub fn invert_depth(&mut self) {
         self.depth.pixels_mut().for_each(|p| p.0[0] = 255 - p.0[0]);
     }
 
-    pub fn slice(&mut self, from: Option<u8>, to: Option<u8>) -> MaskImage {
+    pub fn slice(&self, from: Option<u8>, to: Option<u8>) -> MaskImage {
         let from_parsed = from.unwrap_or(0);
         let to_parsed = to.unwrap_or(u8::MAX);
         let mut mask = ImageBuffer::from_pixel(self.image.width(), self.image.height(), MASK_TRUE);
@@ -244,3 +439,73 @@ impl DepthImage {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) fn test_depth_image(
+    width: u32,
+    height: u32,
+    depth: impl Fn(u32, u32) -> u8,
+) -> DepthImage {
+    let mut img = DepthImage::from_image(DynamicImage::new_rgb8(width, height));
+    img.load_depth(ImageBuffer::from_fn(width, height, |x, y| {
+        Luma([depth(x, y)])
+    }))
+    .unwrap();
+    img
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn depth_image(depth: impl Fn(u32, u32) -> u8) -> DepthImage {
+        test_depth_image(16, 8, depth)
+    }
+
+    #[test]
+    fn depth_split_narrow_range() {
+        for img in [depth_image(|_, _| 0), depth_image(|x, _| (x % 2) as u8)] {
+            assert_eq!(img.depth_split(5), vec![(None, None)]);
+            assert!(img.select_zone(0, 5).is_some());
+            assert!(img.select_zone(1, 5).is_none());
+            img.select_foreground();
+            img.select_background();
+        }
+    }
+
+    #[test]
+    fn select_zone_out_of_range() {
+        let img = depth_image(|x, _| if x < 8 { 10 } else { 200 });
+        assert_eq!(img.depth_split(2).len(), 2);
+        assert!(img.select_zone(1, 2).is_some());
+        assert!(img.select_zone(2, 2).is_none());
+    }
+
+    #[test]
+    fn depth_split_zone_count() {
+        let img = depth_image(|x, _| (x * 10 / 15) as u8);
+        for zones in 2..=11 {
+            let ranges = img.depth_split(zones);
+            assert_eq!(ranges.len(), zones as usize);
+            assert_eq!(ranges[0].0, Some(0));
+            assert_eq!(ranges.last().unwrap().1, Some(10));
+            assert!(ranges.windows(2).all(|r| r[0].1 < r[1].0));
+        }
+        let img = depth_image(|x, _| (x % 2) as u8);
+        assert_eq!(
+            img.depth_split(2),
+            vec![(Some(0), Some(0)), (Some(1), Some(1))]
+        );
+    }
+
+    #[test]
+    fn depth_split_empty_cluster() {
+        let img = depth_image(|x, _| if x < 8 { 100 } else { 200 });
+        assert_eq!(
+            img.depth_split(3),
+            vec![(Some(100), Some(100)), (Some(200), Some(200))]
+        );
+        assert!(img.select_zone(1, 3).is_some());
+        assert!(img.select_zone(2, 3).is_none());
+    }
+}
@@ -0,0 +1,269 @@
+use crate::depth_image::DepthImage;
+use image::{DynamicImage, ImageBuffer, Rgb};
+use rayon::prelude::*;
+
+const DOF_TILE: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BokehShape {
+    Disk,
+    Hexagon,
+}
+
+impl BokehShape {
+    fn contains(&self, dx: f32, dy: f32, radius: f32) -> bool {
+        let (x, y) = (dx.abs(), dy.abs());
+        match self {
+            BokehShape::Disk => x * x + y * y <= radius * radius,
+            BokehShape::Hexagon => {
+                let sqrt3 = 3f32.sqrt();
+                y <= radius * sqrt3 / 2.0 && sqrt3 * x + y <= sqrt3 * radius
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FogFalloff {
+    Linear,
+    Exponential,
+    ExponentialSquared,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LightKind {
+    /// Position in pixel coordinates, `z` is the height above the farthest depth.
+    Point([f32; 3]),
+    /// Direction from the surface towards the light.
+    Directional([f32; 3]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: Rgb<u8>,
+    pub intensity: f32,
+    pub specular: f32,
+    pub shininess: f32,
+}
+
+fn normalize(v: [f32; 3]) -> [f32; 3] {
+    let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    if length == 0.0 {
+        return [0.0, 0.0, 1.0];
+    }
+    [v[0] / length, v[1] / length, v[2] / length]
+}
+
+fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+impl DepthImage {
+    pub fn render_depth_of_field(
+        &self,
+        focus_depth: u8,
+        aperture: f32,
+        max_radius: u32,
+        shape: BokehShape,
+    ) -> DynamicImage {
+        let width = self.width() as usize;
+        let height = self.height() as usize;
+        if width == 0 || height == 0 {
+            return self.image();
+        }
+        let depth = self.depth.as_raw();
+        let image = self.image.as_raw();
+        let coc: Vec<f32> = depth
+            .iter()
+            .map(|d| {
+                ((*d as f32 - focus_depth as f32).abs() / 255.0 * aperture)
+                    .min(max_radius as f32)
+                    .max(0.5)
+            })
+            .collect();
+        // a neighbour only reaches as far as its own blur, so each tile scans a window
+        // bounded by the largest blur within max_radius of it instead of max_radius itself
+        let tiles_x = width / DOF_TILE + 1;
+        let tiles_y = height / DOF_TILE + 1;
+        let mut tile_coc = vec![0f32; tiles_x * tiles_y];
+        for (i, c) in coc.iter().enumerate() {
+            let tile = (i / width / DOF_TILE) * tiles_x + (i % width) / DOF_TILE;
+            tile_coc[tile] = tile_coc[tile].max(*c);
+        }
+        let reach = |tile: usize, tiles: usize| {
+            let from = (tile * DOF_TILE).saturating_sub(max_radius as usize) / DOF_TILE;
+            let to = ((tile + 1) * DOF_TILE + max_radius as usize) / DOF_TILE;
+            from..=to.min(tiles - 1)
+        };
+        let tile_radius: Vec<i64> = (0..tiles_x * tiles_y)
+            .map(|tile| {
+                let (tile_x, tile_y) = (tile % tiles_x, tile / tiles_x);
+                let mut radius = 0f32;
+                for ty in reach(tile_y, tiles_y) {
+                    for tx in reach(tile_x, tiles_x) {
+                        radius = radius.max(tile_coc[ty * tiles_x + tx]);
+                    }
+                }
+                radius.ceil() as i64
+            })
+            .collect();
+        let mut res = self.image.clone();
+        res.par_chunks_mut(width * 4)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for x in 0..width {
+                    let index = y * width + x;
+                    let radius = tile_radius[(y / DOF_TILE) * tiles_x + x / DOF_TILE];
+                    let mut acc = [0f32; 4];
+                    let mut weight = 0f32;
+                    for dy in -radius..=radius {
+                        let qy = y as i64 + dy;
+                        if qy < 0 || qy >= height as i64 {
+                            continue;
+                        }
+                        for dx in -radius..=radius {
+                            let qx = x as i64 + dx;
+                            if qx < 0 || qx >= width as i64 {
+                                continue;
+                            }
+                            let q = qy as usize * width + qx as usize;
+                            // pixels behind the current one can't spread wider than its own blur,
+                            // so a sharp foreground stays sharp over a blurred background
+                            let q_coc = if depth[q] <= depth[index] {
+                                coc[q]
+                            } else {
+                                coc[q].min(coc[index])
+                            };
+                            if !shape.contains(dx as f32, dy as f32, q_coc) {
+                                continue;
+                            }
+                            let w = 1.0 / (q_coc * q_coc);
+                            for (a, v) in acc.iter_mut().zip(&image[q * 4..q * 4 + 4]) {
+                                *a += *v as f32 * w;
+                            }
+                            weight += w;
+                        }
+                    }
+                    for (p, a) in row[x * 4..x * 4 + 4].iter_mut().zip(acc.iter()) {
+                        *p = (a / weight).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+            });
+        DynamicImage::ImageRgba8(res)
+    }
+
+    pub fn apply_fog(
+        &mut self,
+        color: Rgb<u8>,
+        density: f32,
+        start: u8,
+        end: u8,
+        falloff: FogFalloff,
+    ) {
+        let (start, end) = (start as f32, end as f32);
+        self.image
+            .pixels_mut()
+            .zip(self.depth.pixels())
+            .for_each(|(p, d)| {
+                let distance = d.0[0] as f32;
+                let visibility = match falloff {
+                    FogFalloff::Linear => (end - distance) / (end - start).max(1.0),
+                    FogFalloff::Exponential => {
+                        (-density * (distance - start).max(0.0) / 255.0).exp()
+                    }
+                    FogFalloff::ExponentialSquared => {
+                        (-(density * (distance - start).max(0.0) / 255.0).powi(2)).exp()
+                    }
+                }
+                .clamp(0.0, 1.0);
+                for i in 0..3 {
+                    p.0[i] = (p.0[i] as f32 * visibility + color.0[i] as f32 * (1.0 - visibility))
+                        .round() as u8;
+                }
+            });
+    }
+
+    fn normal_vectors(&self) -> Vec<[f32; 3]> {
+        let (width, height) = (self.width() as i64, self.height() as i64);
+        // surfaces rise towards the viewer, so height is the inverted depth
+        let at = |x: i64, y: i64| {
+            (u8::MAX
+                - self
+                    .depth
+                    .get_pixel(x.clamp(0, width - 1) as u32, y.clamp(0, height - 1) as u32)
+                    .0[0]) as f32
+        };
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let gx = (at(x + 1, y) - at(x - 1, y)) / 2.0;
+                let gy = (at(x, y + 1) - at(x, y - 1)) / 2.0;
+                normalize([-gx, -gy, 1.0])
+            })
+            .collect()
+    }
+
+    pub fn normals(&self) -> DynamicImage {
+        let normals = self.normal_vectors();
+        let encode = |v: f32| ((v + 1.0) / 2.0 * 255.0).round() as u8;
+        let res = ImageBuffer::from_fn(self.width(), self.height(), |x, y| {
+            let n = normals[(y * self.width() + x) as usize];
+            // normal maps conventionally point y up, image rows go down
+            Rgb([encode(n[0]), encode(-n[1]), encode(n[2])])
+        });
+        DynamicImage::ImageRgb8(res)
+    }
+
+    pub fn relight(&mut self, lights: &[Light], ambient: f32) {
+        let width = self.width() as usize;
+        if width == 0 || self.height() == 0 {
+            return;
+        }
+        let normals = self.normal_vectors();
+        let depth = self.depth.as_raw();
+        self.image
+            .par_chunks_mut(width * 4)
+            .enumerate()
+            .for_each(|(y, row)| {
+                for x in 0..width {
+                    let index = y * width + x;
+                    let n = normals[index];
+                    let surface = [x as f32, y as f32, (u8::MAX - depth[index]) as f32];
+                    let mut diffuse = [ambient; 3];
+                    let mut specular = [0f32; 3];
+                    for light in lights {
+                        let (l, attenuation) = match light.kind {
+                            LightKind::Directional(direction) => (normalize(direction), 1.0),
+                            LightKind::Point(position) => {
+                                let to_light = [
+                                    position[0] - surface[0],
+                                    position[1] - surface[1],
+                                    position[2] - surface[2],
+                                ];
+                                let distance = dot(to_light, to_light).sqrt().max(1.0);
+                                (normalize(to_light), 1.0 / (1.0 + distance / 255.0).powi(2))
+                            }
+                        };
+                        let lambert = dot(n, l).max(0.0);
+                        if lambert == 0.0 {
+                            continue;
+                        }
+                        let h = normalize([l[0], l[1], l[2] + 1.0]);
+                        let highlight = dot(n, h).max(0.0).powf(light.shininess) * light.specular;
+                        for i in 0..3 {
+                            let color =
+                                light.color.0[i] as f32 / 255.0 * light.intensity * attenuation;
+                            diffuse[i] += lambert * color;
+                            specular[i] += highlight * color;
+                        }
+                    }
+                    for i in 0..3 {
+                        let albedo = row[x * 4 + i] as f32;
+                        row[x * 4 + i] =
+                            (albedo * diffuse[i] + 255.0 * specular[i]).clamp(0.0, 255.0) as u8;
+                    }
+                }
+            });
+    }
+}
@@ -0,0 +1,69 @@
+use crate::depth_image::DepthImage;
+use std::io::{BufWriter, Write};
+
+impl DepthImage {
+    fn mesh_faces(&self) -> impl Iterator<Item = [usize; 3]> + '_ {
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let opaque = move |i: usize| self.image.as_raw()[i * 4 + 3] != 0;
+        (0..height.saturating_sub(1))
+            .flat_map(move |y| (0..width.saturating_sub(1)).map(move |x| y * width + x))
+            .flat_map(move |i| {
+                let quad = [i, i + 1, i + width, i + width + 1];
+                [[quad[0], quad[2], quad[1]], [quad[1], quad[2], quad[3]]]
+            })
+            // transparent pixels are holes in the surface, not geometry
+            .filter(move |face| face.iter().all(|i| opaque(*i)))
+    }
+
+    fn mesh_vertex(&self, i: usize, depth_scale: f32) -> ([f32; 3], [u8; 3]) {
+        let width = self.width() as usize;
+        let (x, y) = ((i % width) as u32, (i / width) as u32);
+        // nearer pixels have lower depth, so they stand out towards the viewer
+        let height = (u8::MAX - self.depth.get_pixel(x, y).0[0]) as f32;
+        let p = self.image.get_pixel(x, y).0;
+        ([x as f32, -(y as f32), height * depth_scale], [p[0], p[1], p[2]])
+    }
+
+    pub fn save_ply(&self, path: &str, depth_scale: f32) -> Result<(), std::io::Error> {
+        let mut out = BufWriter::new(std::fs::File::create(path)?);
+        let vertices = (self.width() * self.height()) as usize;
+        writeln!(out, "ply\nformat ascii 1.0\nelement vertex {}", vertices)?;
+        writeln!(out, "property float x\nproperty float y\nproperty float z")?;
+        writeln!(
+            out,
+            "property uchar red\nproperty uchar green\nproperty uchar blue"
+        )?;
+        writeln!(out, "element face {}", self.mesh_faces().count())?;
+        writeln!(out, "property list uchar int vertex_indices\nend_header")?;
+        for i in 0..vertices {
+            let ([x, y, z], [r, g, b]) = self.mesh_vertex(i, depth_scale);
+            writeln!(out, "{} {} {} {} {} {}", x, y, z, r, g, b)?;
+        }
+        for [a, b, c] in self.mesh_faces() {
+            writeln!(out, "3 {} {} {}", a, b, c)?;
+        }
+        out.flush()
+    }
+
+    pub fn save_obj(&self, path: &str, depth_scale: f32) -> Result<(), std::io::Error> {
+        let mut out = BufWriter::new(std::fs::File::create(path)?);
+        for i in 0..(self.width() * self.height()) as usize {
+            let ([x, y, z], [r, g, b]) = self.mesh_vertex(i, depth_scale);
+            // vertex colors are a widely supported extension of the obj format
+            writeln!(
+                out,
+                "v {} {} {} {:.4} {:.4} {:.4}",
+                x,
+                y,
+                z,
+                r as f32 / 255.0,
+                g as f32 / 255.0,
+                b as f32 / 255.0
+            )?;
+        }
+        for [a, b, c] in self.mesh_faces() {
+            writeln!(out, "f {} {} {}", a + 1, b + 1, c + 1)?;
+        }
+        out.flush()
+    }
+}
@@ -1,4 +1,7 @@
-use disage::{pixels::PixelOpps, Position};
+use crate::depth_image::Connectivity;
+use crate::mask_image::{MASK_FALSE, MASK_TRUE};
+use disage::{pixels::PixelOpps, Dimensions, Position};
+use image::{imageops, DynamicImage, ImageBuffer, Luma, Rgb, Rgba};
 
 pub fn distance_dot_dot(f: Position, s: Position) -> u32 {
     let (x1, y1) = (f.x as i64, f.y as i64);
@@ -52,3 +55,170 @@ pub fn distance_dot_array<T: Clone + PixelOpps<T>>(
     }
     None
 }
+
+pub fn flood_fill<F: Fn(Position, Position) -> bool>(
+    dimensions: Dimensions,
+    seed: Position,
+    connectivity: Connectivity,
+    accept: F,
+) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    let (width, height) = (dimensions.width, dimensions.height);
+    let mut mask = ImageBuffer::from_pixel(width, height, MASK_FALSE);
+    if seed.x >= width || seed.y >= height {
+        return mask;
+    }
+    let offsets: Vec<(i64, i64)> = (-1..=1)
+        .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+        .filter(|(dx, dy)| {
+            (*dx, *dy) != (0, 0) && (connectivity == Connectivity::Eight || dx * dy == 0)
+        })
+        .collect();
+    mask.put_pixel(seed.x, seed.y, MASK_TRUE);
+    let mut stack = vec![seed];
+    while let Some(current) = stack.pop() {
+        for (dx, dy) in offsets.iter() {
+            let (x, y) = (current.x as i64 + dx, current.y as i64 + dy);
+            if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+                continue;
+            }
+            let next = Position::new(x as u32, y as u32);
+            if *mask.get_pixel(next.x, next.y) == MASK_TRUE || !accept(current, next) {
+                continue;
+            }
+            mask.put_pixel(next.x, next.y, MASK_TRUE);
+            stack.push(next);
+        }
+    }
+    mask
+}
+
+pub fn blend_tint(pixel: &mut Rgba<u8>, tint: Rgb<u8>, opacity: f32) {
+    let opacity = opacity.clamp(0.0, 1.0);
+    for i in 0..3 {
+        pixel.0[i] =
+            (pixel.0[i] as f32 * (1.0 - opacity) + tint.0[i] as f32 * opacity).round() as u8;
+    }
+}
+
+pub fn side_by_side(
+    left: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    right: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> DynamicImage {
+    let mut res = ImageBuffer::new(
+        left.width() + right.width(),
+        left.height().max(right.height()),
+    );
+    imageops::replace(&mut res, left, 0, 0);
+    imageops::replace(&mut res, right, left.width(), 0);
+    DynamicImage::ImageRgba8(res)
+}
+
+pub fn morphology(
+    mask: &ImageBuffer<Luma<u8>, Vec<u8>>,
+    radius: u32,
+    erode: bool,
+) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    let pick = |values: &mut dyn Iterator<Item = u8>| {
+        if erode {
+            values.min().unwrap()
+        } else {
+            values.max().unwrap()
+        }
+    };
+    let (width, height) = mask.dimensions();
+    let r = radius as i64;
+    let clamp = |v: i64, size: u32| v.clamp(0, size as i64 - 1) as u32;
+    let rows = ImageBuffer::from_fn(width, height, |x, y| {
+        Luma([pick(
+            &mut (-r..=r).map(|d| mask.get_pixel(clamp(x as i64 + d, width), y).0[0]),
+        )])
+    });
+    ImageBuffer::from_fn(width, height, |x, y| {
+        Luma([pick(
+            &mut (-r..=r).map(|d| rows.get_pixel(x, clamp(y as i64 + d, height)).0[0]),
+        )])
+    })
+}
+
+pub fn apply_matrix(m: &[[f32; 3]; 3], rgb: [f32; 3]) -> [f32; 3] {
+    [0, 1, 2].map(|i| m[i][0] * rgb[0] + m[i][1] * rgb[1] + m[i][2] * rgb[2])
+}
+
+pub fn saturate(rgb: [f32; 3], amount: f32) -> [f32; 3] {
+    let luma = 0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2];
+    rgb.map(|v| luma + (v - luma) * amount)
+}
+
+pub fn curve_table(points: &[(u8, u8)]) -> [u8; 256] {
+    let mut points: Vec<(f32, f32)> = points.iter().map(|(x, y)| (*x as f32, *y as f32)).collect();
+    // the curve is pinned to black and white unless the caller moves its ends
+    points.extend([(0.0, 0.0), (255.0, 255.0)]);
+    points.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    points.dedup_by(|a, b| a.0 == b.0);
+    let mut table = [0u8; 256];
+    // monotone cubic (Fritsch-Carlson) tangents keep the curve from overshooting between points
+    let n = points.len();
+    let slopes: Vec<f32> = points
+        .windows(2)
+        .map(|w| (w[1].1 - w[0].1) / (w[1].0 - w[0].0))
+        .collect();
+    let mut tangents = vec![0f32; n];
+    tangents[0] = slopes[0];
+    tangents[n - 1] = slopes[n - 2];
+    for i in 1..n - 1 {
+        tangents[i] = if slopes[i - 1] * slopes[i] <= 0.0 {
+            0.0
+        } else {
+            (slopes[i - 1] + slopes[i]) / 2.0
+        };
+    }
+    for i in 0..n - 1 {
+        if slopes[i] == 0.0 {
+            tangents[i] = 0.0;
+            tangents[i + 1] = 0.0;
+            continue;
+        }
+        let (a, b) = (tangents[i] / slopes[i], tangents[i + 1] / slopes[i]);
+        let length = (a * a + b * b).sqrt();
+        if length > 3.0 {
+            tangents[i] = 3.0 * a / length * slopes[i];
+            tangents[i + 1] = 3.0 * b / length * slopes[i];
+        }
+    }
+    for (v, entry) in table.iter_mut().enumerate() {
+        let x = v as f32;
+        let y = if x <= points[0].0 {
+            points[0].1
+        } else if x >= points[n - 1].0 {
+            points[n - 1].1
+        } else {
+            let i = points.windows(2).position(|w| x <= w[1].0).unwrap();
+            let ((x0, y0), (x1, y1)) = (points[i], points[i + 1]);
+            let h = x1 - x0;
+            let t = (x - x0) / h;
+            let (t2, t3) = (t * t, t * t * t);
+            (2.0 * t3 - 3.0 * t2 + 1.0) * y0
+                + (t3 - 2.0 * t2 + t) * h * tangents[i]
+                + (-2.0 * t3 + 3.0 * t2) * y1
+                + (t3 - t2) * h * tangents[i + 1]
+        };
+        *entry = y.round().clamp(0.0, 255.0) as u8;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_table_keeps_ends() {
+        let identity = curve_table(&[]);
+        assert!(identity.iter().enumerate().all(|(v, e)| *e as usize == v));
+        let table = curve_table(&[(128, 160)]);
+        assert_eq!((table[0], table[128], table[255]), (0, 160, 255));
+        assert!(table.windows(2).all(|w| w[0] <= w[1]));
+        let table = curve_table(&[(0, 40), (255, 200)]);
+        assert_eq!((table[0], table[255]), (40, 200));
+    }
+}
@@ -1,9 +1,18 @@
 #![feature(int_log)]
 
+pub mod background;
+pub mod colormap;
+pub mod contours;
 pub mod depth_image;
+pub mod effects;
+pub mod export;
 pub mod mask_image;
 mod helpers;
 pub mod operations;
+pub mod pipeline;
+pub mod progress;
+pub mod stats;
+pub mod views;
 
 #[allow(unused_imports)]
 pub use crate::depth_image::*;
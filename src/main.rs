@@ -0,0 +1,339 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use image::DynamicImage;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use stepth::colormap::Colormap;
+use stepth::pipeline::EditPipeline;
+use stepth::progress::Progress;
+use stepth::{BlendMode, DepthImage, MaskImage};
+
+#[derive(Parser)]
+#[command(
+    name = "stepth",
+    version,
+    about = "Depth based image selection and editing"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Args)]
+struct DepthInput {
+    image: PathBuf,
+    depth: PathBuf,
+    /// Invert the depth map first, for maps where nearer is brighter
+    #[arg(long)]
+    invert_depth: bool,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Estimate depth from an additional shot of the same scene
+    Depth {
+        image: PathBuf,
+        additional: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Brightness precision per channel, as R,G,B
+        #[arg(long, default_value = "16,16,16", value_parser = parse_precision)]
+        precision: [u8; 3],
+    },
+    /// Select pixels by depth and save the mask
+    Mask {
+        #[command(flatten)]
+        input: DepthInput,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long)]
+        foreground: bool,
+        #[arg(long)]
+        background: bool,
+        /// Depth range as FROM:TO, either side may be left empty
+        #[arg(long, value_parser = parse_range)]
+        slice: Vec<(Option<u8>, Option<u8>)>,
+        /// Depth zone as INDEX/ZONES
+        #[arg(long, value_parser = parse_zone)]
+        zone: Vec<(usize, u8)>,
+        #[arg(long)]
+        invert: bool,
+        #[arg(long, default_value_t = 0.0)]
+        feather: f32,
+        /// Save the image with the selection highlighted instead of the mask
+        #[arg(long)]
+        highlight: bool,
+    },
+    /// Run a JSON or TOML edit pipeline
+    Apply {
+        #[command(flatten)]
+        input: DepthInput,
+        pipeline: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Render the depth map through a colormap
+    Colorize {
+        depth: PathBuf,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, value_enum, default_value_t = ColormapArg::Turbo)]
+        colormap: ColormapArg,
+        /// Depth range mapped onto the colormap as FROM:TO
+        #[arg(long, value_parser = parse_range)]
+        range: Option<(Option<u8>, Option<u8>)>,
+        #[arg(long)]
+        legend: bool,
+    },
+    /// Blur the background, keeping the foreground sharp
+    BlurBg {
+        #[command(flatten)]
+        input: DepthInput,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = 8.0)]
+        radius: f32,
+        #[arg(long, default_value_t = 3.0)]
+        feather: f32,
+    },
+    /// Export the depth image as a colored mesh, format chosen by extension
+    Export {
+        #[command(flatten)]
+        input: DepthInput,
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, default_value_t = 0.5)]
+        depth_scale: f32,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ColormapArg {
+    Turbo,
+    Viridis,
+    Magma,
+    Jet,
+    Inferno,
+}
+
+impl From<ColormapArg> for Colormap {
+    fn from(arg: ColormapArg) -> Self {
+        match arg {
+            ColormapArg::Turbo => Colormap::Turbo,
+            ColormapArg::Viridis => Colormap::Viridis,
+            ColormapArg::Magma => Colormap::Magma,
+            ColormapArg::Jet => Colormap::Jet,
+            ColormapArg::Inferno => Colormap::Inferno,
+        }
+    }
+}
+
+fn parse_precision(s: &str) -> Result<[u8; 3], String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<u8>().map_err(|e| e.to_string()))
+        .collect::<Result<Vec<u8>, String>>()?;
+    match values[..] {
+        [r, g, b] => Ok([r, g, b]),
+        [v] => Ok([v; 3]),
+        _ => Err("expected R,G,B".to_string()),
+    }
+}
+
+fn parse_range(s: &str) -> Result<(Option<u8>, Option<u8>), String> {
+    let (from, to) = s.split_once(':').ok_or("expected FROM:TO")?;
+    let bound = |v: &str| match v.trim() {
+        "" => Ok(None),
+        v => v.parse::<u8>().map(Some).map_err(|e| e.to_string()),
+    };
+    Ok((bound(from)?, bound(to)?))
+}
+
+fn parse_zone(s: &str) -> Result<(usize, u8), String> {
+    let (index, zones) = s.split_once('/').ok_or("expected INDEX/ZONES")?;
+    Ok((
+        index
+            .trim()
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string())?,
+        zones
+            .trim()
+            .parse()
+            .map_err(|e: std::num::ParseIntError| e.to_string())?,
+    ))
+}
+
+fn step<T, F: FnOnce() -> T>(message: &str, f: F) -> T {
+    let bar = ProgressBar::new_spinner();
+    bar.set_style(ProgressStyle::with_template("{spinner} {msg} [{elapsed}]").unwrap());
+    bar.set_message(message.to_string());
+    bar.enable_steady_tick(Duration::from_millis(100));
+    let res = f();
+    bar.finish();
+    res
+}
+
+fn path(p: &Path) -> Result<&str, std::io::Error> {
+    p.to_str().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Path is not valid UTF-8")
+    })
+}
+
+impl DepthInput {
+    fn open(&self) -> Result<DepthImage, std::io::Error> {
+        let mut res = step("Loading images", || DepthImage::open(path(&self.image)?))?;
+        step("Loading depth", || res.open_depth(path(&self.depth)?))?;
+        if self.invert_depth {
+            res.invert_depth();
+        }
+        Ok(res)
+    }
+}
+
+fn run(command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Depth {
+            image,
+            additional,
+            output,
+            precision,
+        } => {
+            let mut img = step("Loading images", || DepthImage::open(path(&image)?))?;
+            let bar = ProgressBar::new(0);
+            bar.set_style(
+                ProgressStyle::with_template("{msg} {wide_bar} {percent}% [{elapsed}<{eta}]")
+                    .unwrap(),
+            );
+            bar.set_message("Estimating depth");
+            let progress = Progress {
+                reporter: Some(&bar),
+                cancel: None,
+            };
+            img.open_depth_from_additional_with(path(&additional)?, precision, progress)?;
+            bar.finish();
+            step("Saving depth", || img.depth().save(&output))?;
+        }
+        Command::Mask {
+            input,
+            output,
+            foreground,
+            background,
+            slice,
+            zone,
+            invert,
+            feather,
+            highlight,
+        } => {
+            let img = input.open()?;
+            let mut res = MaskImage::from_image(img.image());
+            step("Selecting", || -> Result<(), std::io::Error> {
+                let mut selections = Vec::new();
+                if foreground {
+                    selections.push(img.select_foreground());
+                }
+                if background {
+                    selections.push(img.select_background());
+                }
+                for (from, to) in slice {
+                    selections.push(img.slice(from, to));
+                }
+                for (index, zones) in zone {
+                    selections.push(img.select_zone(index, zones).ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::InvalidInput, "Zone doesn't exist")
+                    })?);
+                }
+                let mut selections = selections.into_iter();
+                if let Some(first) = selections.next() {
+                    res.mask_copy(&first);
+                }
+                selections.for_each(|s| res.mask_or(&s));
+                if invert {
+                    res.mask_not();
+                }
+                res.mask_feather(feather);
+                Ok(())
+            })?;
+            step("Saving mask", || {
+                if highlight {
+                    res.highlight_mask().save(&output)
+                } else {
+                    res.mask().save(&output)
+                }
+            })?;
+        }
+        Command::Apply {
+            input,
+            pipeline,
+            output,
+        } => {
+            let img = input.open()?;
+            let text = std::fs::read_to_string(&pipeline)?;
+            let pipeline = match pipeline.extension().and_then(|e| e.to_str()) {
+                Some("toml") => EditPipeline::from_toml(&text)?,
+                _ => EditPipeline::from_json(&text)?,
+            };
+            let res = step("Applying edits", || pipeline.apply(&img))?;
+            step("Saving image", || res.save(path(&output)?))?;
+        }
+        Command::Colorize {
+            depth,
+            output,
+            colormap,
+            range,
+            legend,
+        } => {
+            let depth = step("Loading depth", || image::open(&depth))?.to_luma8();
+            let mut img =
+                DepthImage::from_image(DynamicImage::new_rgba8(depth.width(), depth.height()));
+            img.load_depth(depth)?;
+            let range = range.map(|(from, to)| (from.unwrap_or(u8::MIN), to.unwrap_or(u8::MAX)));
+            let res = step("Colorizing", || {
+                img.depth_colorized(&colormap.into(), range, legend)
+            });
+            step("Saving image", || res.save(&output))?;
+        }
+        Command::BlurBg {
+            input,
+            output,
+            radius,
+            feather,
+        } => {
+            let img = input.open()?;
+            let mut res = MaskImage::from_image(img.image());
+            step("Blurring background", || {
+                // composite a blurred copy through the feathered mask for a soft transition
+                let blurred = MaskImage::from_image(img.image().blur(radius));
+                res.mask_copy(&img.select_background());
+                res.mask_feather(feather);
+                res.composite(&blurred, disage::Position::new(0, 0), BlendMode::Normal);
+            });
+            step("Saving image", || res.save(path(&output)?))?;
+        }
+        Command::Export {
+            input,
+            output,
+            depth_scale,
+        } => {
+            let img = input.open()?;
+            step("Exporting mesh", || {
+                match output.extension().and_then(|e| e.to_str()) {
+                    Some("ply") => img.save_ply(path(&output)?, depth_scale),
+                    Some("obj") => img.save_obj(path(&output)?, depth_scale),
+                    _ => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Output must be a .ply or .obj file",
+                    )),
+                }
+            })?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = run(Cli::parse().command) {
+        eprintln!("stepth: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -1,8 +1,96 @@
-use image::{DynamicImage, ImageBuffer, Luma};
+use crate::{helpers, operations};
+use image::{imageops, DynamicImage, ImageBuffer, Luma, Rgb};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 pub const MASK_TRUE: Luma<u8> = Luma([u8::MAX; 1]);
 pub const MASK_FALSE: Luma<u8> = Luma([u8::MIN; 1]);
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode {
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    Add,
+    Difference,
+}
+
+impl BlendMode {
+    pub fn blend(&self, base: f32, top: f32) -> f32 {
+        match self {
+            BlendMode::Normal => top,
+            BlendMode::Multiply => base * top,
+            BlendMode::Screen => 1.0 - (1.0 - base) * (1.0 - top),
+            BlendMode::Overlay => {
+                if base < 0.5 {
+                    2.0 * base * top
+                } else {
+                    1.0 - 2.0 * (1.0 - base) * (1.0 - top)
+                }
+            }
+            BlendMode::Add => (base + top).min(1.0),
+            BlendMode::Difference => (base - top).abs(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub position: disage::Position,
+    pub size: disage::Dimensions,
+}
+
+impl Rect {
+    pub fn pad(&self, padding: u32) -> Rect {
+        let (x, y) = self.position.tuplexy();
+        let (from_x, from_y) = (x.saturating_sub(padding), y.saturating_sub(padding));
+        Rect {
+            position: disage::Position::new(from_x, from_y),
+            size: disage::Dimensions {
+                width: self.size.width + (x - from_x) + padding,
+                height: self.size.height + (y - from_y) + padding,
+            },
+        }
+    }
+
+    pub fn clamp_to(&self, dimensions: disage::Dimensions) -> Rect {
+        let x = self.position.x.min(dimensions.width);
+        let y = self.position.y.min(dimensions.height);
+        Rect {
+            position: disage::Position::new(x, y),
+            size: disage::Dimensions {
+                width: self.size.width.min(dimensions.width - x),
+                height: self.size.height.min(dimensions.height - y),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaskHighlight {
+    pub gain: [f32; 3],
+    pub tint: Rgb<u8>,
+    pub opacity: f32,
+    pub checkerboard: Option<u32>,
+    pub outline: Option<Rgb<u8>>,
+    pub side_by_side: bool,
+}
+
+impl Default for MaskHighlight {
+    fn default() -> Self {
+        MaskHighlight {
+            gain: [2.0, 0.5, 0.5],
+            tint: Rgb([u8::MAX, 0, 0]),
+            opacity: 0.0,
+            checkerboard: None,
+            outline: None,
+            side_by_side: false,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct MaskImage {
     pub image: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
@@ -55,23 +143,48 @@ impl MaskImage {
     }
 
     pub fn highlight_mask(&self) -> DynamicImage {
+        self.highlight_mask_with(&MaskHighlight::default())
+    }
+
+    pub fn highlight_mask_with(&self, options: &MaskHighlight) -> DynamicImage {
         let mut res = self.image.clone();
-        res.pixels_mut().zip(self.mask.pixels()).for_each(|(p, d)| {
-            if *d == MASK_TRUE {
-                let multiplier = 2.0;
-                let adjust = |v: u8, pos: bool| {
-                    (v as f32 * if pos { multiplier } else { 1.0 / multiplier })
-                        .max(0.0)
-                        .min(255.0) as u8
+        for (x, y, p) in res.enumerate_pixels_mut() {
+            if *self.mask.get_pixel(x, y) == MASK_TRUE {
+                for (c, gain) in p.0[..3].iter_mut().zip(options.gain) {
+                    *c = (*c as f32 * gain).clamp(0.0, 255.0) as u8;
+                }
+                helpers::blend_tint(p, options.tint, options.opacity);
+                match options.outline {
+                    Some(outline) if self.is_boundary(x, y) => {
+                        p.0[..3].copy_from_slice(&outline.0);
+                    }
+                    _ => {}
+                }
+            } else if let Some(cell) = options.checkerboard {
+                let cell = cell.max(1);
+                let v = if (x / cell + y / cell) % 2 == 0 {
+                    204
+                } else {
+                    153
                 };
-                p.0[0] = adjust(p.0[0], true);
-                p.0[1] = adjust(p.0[1], false);
-                p.0[2] = adjust(p.0[2], false);
+                p.0 = [v, v, v, u8::MAX];
             }
-        });
+        }
+        if options.side_by_side {
+            return helpers::side_by_side(&self.image, &res);
+        }
         DynamicImage::ImageRgba8(res)
     }
 
+    fn is_boundary(&self, x: u32, y: u32) -> bool {
+        if x == 0 || y == 0 || x + 1 == self.width() || y + 1 == self.height() {
+            return true;
+        }
+        [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+            .iter()
+            .any(|(nx, ny)| *self.mask.get_pixel(*nx, *ny) != MASK_TRUE)
+    }
+
     pub fn width(&self) -> u32 {
         self.image.width()
     }
@@ -96,48 +209,297 @@ impl MaskImage {
         }
     }
 
+    pub fn bounding_box(&self) -> Option<Rect> {
+        let width = self.width().max(1) as usize;
+        let (min_x, min_y, max_x, max_y) = self
+            .mask
+            .par_chunks(width)
+            .enumerate()
+            .filter_map(|(y, row)| {
+                let first = row.iter().position(|v| *v == MASK_TRUE.0[0])?;
+                let last = row.iter().rposition(|v| *v == MASK_TRUE.0[0])?;
+                Some((first, y, last, y))
+            })
+            .reduce(
+                || (usize::MAX, usize::MAX, 0, 0),
+                |a, b| (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)),
+            );
+        if min_x > max_x {
+            return None;
+        }
+        Some(Rect {
+            position: disage::Position::new(min_x as u32, min_y as u32),
+            size: disage::Dimensions {
+                width: (max_x - min_x + 1) as u32,
+                height: (max_y - min_y + 1) as u32,
+            },
+        })
+    }
+
+    pub fn crop(&self, rect: Rect) -> MaskImage {
+        let rect = rect.clamp_to(self.dimensions());
+        let (x, y) = rect.position.tuplexy();
+        let (width, height) = (rect.size.width, rect.size.height);
+        MaskImage {
+            image: imageops::crop_imm(&self.image, x, y, width, height).to_image(),
+            mask: imageops::crop_imm(&self.mask, x, y, width, height).to_image(),
+        }
+    }
+
+    pub fn trim_to_mask(&self, padding: u32) -> Option<MaskImage> {
+        Some(self.crop(self.bounding_box()?.pad(padding)))
+    }
+
     pub fn image_replace(&mut self, other: &MaskImage, start_point: disage::Position) {
         let (start_x, start_y) = start_point.tuplexy();
-        for y in start_y..(start_y + other.height()).min(self.height()) {
-            for x in start_x..(start_x + other.width()).min(self.width()) {
-                if *self.mask.get_pixel(x, y) != MASK_TRUE {
+        for y in start_y..(start_y.saturating_add(other.height())).min(self.height()) {
+            for x in start_x..(start_x.saturating_add(other.width())).min(self.width()) {
+                let (other_x, other_y) = (x - start_x, y - start_y);
+                if *self.mask.get_pixel(x, y) != MASK_TRUE
+                    || *other.mask.get_pixel(other_x, other_y) != MASK_TRUE
+                {
                     continue;
                 }
-                self.image.put_pixel(x, y, *other.image.get_pixel(x, y));
+                self.image
+                    .put_pixel(x, y, *other.image.get_pixel(other_x, other_y));
             }
         }
     }
 
+    pub fn composite(
+        &mut self,
+        other: &MaskImage,
+        offset: disage::Position,
+        blend_mode: BlendMode,
+    ) {
+        let (start_x, start_y) = offset.tuplexy();
+        for y in start_y..(start_y.saturating_add(other.height())).min(self.height()) {
+            for x in start_x..(start_x.saturating_add(other.width())).min(self.width()) {
+                let (other_x, other_y) = (x - start_x, y - start_y);
+                let top = other.image.get_pixel(other_x, other_y);
+                let alpha = self.mask.get_pixel(x, y).0[0] as f32 / 255.0
+                    * other.mask.get_pixel(other_x, other_y).0[0] as f32
+                    / 255.0
+                    * top.0[3] as f32
+                    / 255.0;
+                if alpha == 0.0 {
+                    continue;
+                }
+                let base = self.image.get_pixel_mut(x, y);
+                for i in 0..3 {
+                    let (a, b) = (base.0[i] as f32 / 255.0, top.0[i] as f32 / 255.0);
+                    let blended = blend_mode.blend(a, b);
+                    base.0[i] = ((a + (blended - a) * alpha) * 255.0).round() as u8;
+                }
+            }
+        }
+    }
+
+    fn map_masked_pixels<F: Fn(&mut [u8]) + Sync>(&mut self, f: F) {
+        let rect = match self.bounding_box() {
+            Some(rect) => rect,
+            None => return,
+        };
+        let width = self.width() as usize;
+        let (x, y) = (rect.position.x as usize, rect.position.y as usize);
+        let (w, h) = (rect.size.width as usize, rect.size.height as usize);
+        let mask = &self.mask;
+        self.image
+            .par_chunks_mut(width * 4)
+            .zip(mask.par_chunks(width))
+            .skip(y)
+            .take(h)
+            .for_each(|(row, mask_row)| {
+                row[x * 4..(x + w) * 4]
+                    .chunks_exact_mut(4)
+                    .zip(&mask_row[x..x + w])
+                    .filter(|(_, m)| **m == MASK_TRUE.0[0])
+                    .for_each(|(p, _)| f(p))
+            });
+    }
+
+    fn filter_masked_region<F: Fn(DynamicImage) -> DynamicImage + Sync>(
+        &mut self,
+        margin: u32,
+        filter: F,
+    ) {
+        let rect = match self.bounding_box() {
+            Some(rect) => rect,
+            None => return,
+        };
+        // filters look at neighbours, so process a margin around the mask but only copy masked pixels
+        let region = rect.pad(margin).clamp_to(self.dimensions());
+        let (region_x, region_y) = region.position.tuplexy();
+        let region_bottom = region_y + region.size.height;
+        let width = self.width() as usize;
+        let region_width = region.size.width as usize;
+        let (x, y) = (rect.position.x as usize, rect.position.y as usize);
+        let (w, h) = (rect.size.width as usize, rect.size.height as usize);
+        // each band is filtered with a margin of rows from its neighbours, so bands join seamlessly
+        let threads = rayon::current_num_threads();
+        let band = h.div_ceil(threads).max(2 * margin as usize).max(1);
+        let image = &self.image;
+        let bands = (y..y + h)
+            .step_by(band)
+            .collect::<Vec<usize>>()
+            .into_par_iter()
+            .map(|band_y| {
+                let top = (band_y as u32).saturating_sub(margin).max(region_y);
+                let bottom = ((band_y + band) as u32 + margin).min(region_bottom);
+                let filtered = filter(DynamicImage::ImageRgba8(
+                    imageops::crop_imm(image, region_x, top, region.size.width, bottom - top)
+                        .to_image(),
+                ))
+                .to_rgba8();
+                (top as usize, filtered)
+            })
+            .collect::<Vec<_>>();
+        let region_x = region_x as usize;
+        let mask = &self.mask;
+        self.image
+            .par_chunks_mut(width * 4)
+            .zip(mask.par_chunks(width))
+            .enumerate()
+            .skip(y)
+            .take(h)
+            .for_each(|(row_y, (row, mask_row))| {
+                let (top, filtered) = &bands[(row_y - y) / band];
+                let from = (row_y - top) * region_width * 4;
+                let filtered_row = &filtered.as_raw()[from..from + region_width * 4];
+                for column in x..x + w {
+                    if mask_row[column] != MASK_TRUE.0[0] {
+                        continue;
+                    }
+                    let filtered_x = (column - region_x) * 4;
+                    row[column * 4..column * 4 + 4]
+                        .copy_from_slice(&filtered_row[filtered_x..filtered_x + 4]);
+                }
+            });
+    }
+
     pub fn image_brightness(&mut self, value: i32) {
-        let image_to_mod = DynamicImage::ImageRgba8(self.image.clone()).brighten(value);
-        self.image_replace(
-            &MaskImage::from_image(image_to_mod),
-            disage::Position::new(0, 0),
-        );
+        self.map_masked_pixels(|p| {
+            for c in p[..3].iter_mut() {
+                *c = (*c as i32 + value).clamp(0, 255) as u8;
+            }
+        });
     }
 
     pub fn image_contrast(&mut self, value: i32) {
-        let image_to_mod = DynamicImage::ImageRgba8(self.image.clone()).adjust_contrast(value as f32);
-        self.image_replace(
-            &MaskImage::from_image(image_to_mod),
-            disage::Position::new(0, 0),
-        );
+        let percent = ((100.0 + value as f32) / 100.0).powi(2);
+        self.map_masked_pixels(|p| {
+            for c in p.iter_mut() {
+                *c = (((*c as f32 / 255.0 - 0.5) * percent + 0.5) * 255.0).clamp(0.0, 255.0) as u8;
+            }
+        });
     }
 
     pub fn image_sharpness(&mut self, value: i32) {
-        let image_to_mod = DynamicImage::ImageRgba8(self.image.clone()).unsharpen(value as f32, 20);
-        self.image_replace(
-            &MaskImage::from_image(image_to_mod),
-            disage::Position::new(0, 0),
-        );
+        let margin = (value.max(0) as f32 * 3.0).ceil() as u32 + 1;
+        self.filter_masked_region(margin, |img| img.unsharpen(value as f32, 20));
     }
 
     pub fn image_blur(&mut self, value: i32) {
-        let image_to_mod = DynamicImage::ImageRgba8(self.image.clone()).blur(value as f32);
-        self.image_replace(
-            &MaskImage::from_image(image_to_mod),
-            disage::Position::new(0, 0),
-        );
+        let margin = (value.max(0) as f32 * 3.0).ceil() as u32 + 1;
+        self.filter_masked_region(margin, |img| img.blur(value as f32));
+    }
+
+    fn map_masked_channels(&mut self, table: [u8; 256]) {
+        self.map_masked_pixels(|p| {
+            for c in p[..3].iter_mut() {
+                *c = table[*c as usize];
+            }
+        });
+    }
+
+    fn map_masked_rgb<F: Fn([f32; 3]) -> [f32; 3] + Sync>(&mut self, f: F) {
+        self.map_masked_pixels(|p| {
+            let rgb = f([p[0] as f32, p[1] as f32, p[2] as f32]);
+            for (c, v) in p[..3].iter_mut().zip(rgb.iter()) {
+                *c = v.round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    }
+
+    pub fn image_hue(&mut self, degrees: f32) {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let m = [
+            [
+                0.213 + cos * 0.787 - sin * 0.213,
+                0.715 - cos * 0.715 - sin * 0.715,
+                0.072 - cos * 0.072 + sin * 0.928,
+            ],
+            [
+                0.213 - cos * 0.213 + sin * 0.143,
+                0.715 + cos * 0.285 + sin * 0.140,
+                0.072 - cos * 0.072 - sin * 0.283,
+            ],
+            [
+                0.213 - cos * 0.213 - sin * 0.787,
+                0.715 - cos * 0.715 + sin * 0.715,
+                0.072 + cos * 0.928 + sin * 0.072,
+            ],
+        ];
+        self.map_masked_rgb(|rgb| helpers::apply_matrix(&m, rgb));
+    }
+
+    pub fn image_saturation(&mut self, amount: f32) {
+        self.map_masked_rgb(|rgb| helpers::saturate(rgb, amount));
+    }
+
+    pub fn image_vibrance(&mut self, amount: f32) {
+        self.map_masked_rgb(|rgb| {
+            let max = rgb.iter().cloned().fold(0.0, f32::max);
+            let min = rgb.iter().cloned().fold(255.0, f32::min);
+            let saturation = if max > 0.0 { (max - min) / max } else { 0.0 };
+            // muted colours get most of the boost, already vivid ones are left mostly alone
+            helpers::saturate(rgb, 1.0 + amount * (1.0 - saturation))
+        });
+    }
+
+    pub fn image_desaturate(&mut self) {
+        self.image_saturation(0.0);
+    }
+
+    pub fn image_sepia(&mut self) {
+        let m = [
+            [0.393, 0.769, 0.189],
+            [0.349, 0.686, 0.168],
+            [0.272, 0.534, 0.131],
+        ];
+        self.map_masked_rgb(|rgb| helpers::apply_matrix(&m, rgb));
+    }
+
+    pub fn image_levels(&mut self, black: u8, white: u8, gamma: f32) {
+        let range = (white as f32 - black as f32).max(1.0);
+        let mut table = [0u8; 256];
+        for (v, entry) in table.iter_mut().enumerate() {
+            let normalized = ((v as f32 - black as f32) / range).clamp(0.0, 1.0);
+            *entry = (normalized.powf(1.0 / gamma.max(0.01)) * 255.0).round() as u8;
+        }
+        self.map_masked_channels(table);
+    }
+
+    pub fn image_curve(&mut self, points: &[(u8, u8)]) {
+        self.map_masked_channels(helpers::curve_table(points));
+    }
+
+    fn map_masked_linear_gains(&mut self, gains: [f64; 3]) {
+        let table = operations::linear_table();
+        self.map_masked_pixels(|p| operations::apply_linear_gain(p, &table, gains));
+    }
+
+    pub fn image_white_balance(&mut self, method: operations::WhiteBalance) {
+        let gains = operations::white_balance_gains(&self.image, Some(&self.mask), method);
+        self.map_masked_linear_gains(gains);
+    }
+
+    pub fn image_temperature(&mut self, temperature: f64, tint: f64) {
+        self.map_masked_linear_gains(operations::temperature_gains(temperature, tint));
+    }
+
+    pub fn image_exposure(&mut self, stops: f64) {
+        self.map_masked_linear_gains(operations::exposure_gains(stops));
     }
 
     pub fn mask_copy(&mut self, other: &MaskImage) {
@@ -194,6 +556,20 @@ impl MaskImage {
         self.mask.pixels_mut().for_each(|p| p.0[0] = 255 - p.0[0]);
     }
 
+    pub fn mask_erode(&mut self, radius: u32) {
+        self.mask = helpers::morphology(&self.mask, radius, true);
+    }
+
+    pub fn mask_dilate(&mut self, radius: u32) {
+        self.mask = helpers::morphology(&self.mask, radius, false);
+    }
+
+    pub fn mask_feather(&mut self, radius: f32) {
+        if radius > 0.0 {
+            self.mask = imageops::blur(&self.mask, radius);
+        }
+    }
+
     pub fn save(&self, path: &str) -> Result<(), image::ImageError> {
         Ok(self.image.save(path)?)
     }
@@ -212,3 +588,35 @@ impl MaskImage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn image_replace_at_offset() {
+        let mut img = MaskImage::from_image(DynamicImage::new_rgba8(8, 6));
+        img.mask.put_pixel(5, 3, MASK_FALSE);
+        let mut other = MaskImage::from_image(DynamicImage::ImageRgba8(ImageBuffer::from_pixel(
+            3,
+            2,
+            Rgba([u8::MAX, 0, 0, u8::MAX]),
+        )));
+        other.mask.put_pixel(2, 1, MASK_FALSE);
+        img.image_replace(&other, disage::Position::new(4, 3));
+        for (x, y, p) in img.image.enumerate_pixels() {
+            let inside = (4..7).contains(&x) && (3..5).contains(&y);
+            let pasted = inside && (x, y) != (5, 3) && (x, y) != (6, 4);
+            let expected = if pasted {
+                Rgba([u8::MAX, 0, 0, u8::MAX])
+            } else {
+                Rgba([0; 4])
+            };
+            assert_eq!(*p, expected, "({}, {})", x, y);
+        }
+        let before = img.image.clone();
+        img.image_replace(&other, disage::Position::new(u32::MAX - 1, u32::MAX - 1));
+        assert_eq!(img.image, before);
+    }
+}
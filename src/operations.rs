@@ -1,80 +1,444 @@
-use image::{self, ImageBuffer, Luma, Rgb};
+use crate::mask_image::{MaskImage, MASK_TRUE};
+use crate::stats::ChannelStats;
+use image::{self, ImageBuffer, Luma, Pixel, Rgb};
+use num_traits::{NumCast, ToPrimitive};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-pub fn normalize_brightness_luma16(
-    img1: &ImageBuffer<Luma<u16>, Vec<u16>>,
-    img2: &ImageBuffer<Luma<u16>, Vec<u16>>,
+fn color_channels<P: Pixel>() -> usize {
+    P::CHANNEL_COUNT as usize - P::COLOR_TYPE.has_alpha() as usize
+}
+
+fn subpixel_max<T: NumCast + ToPrimitive + num_traits::Bounded>() -> f64 {
+    // float images are nominally in 0..1, integer ones span their whole range
+    if <T as NumCast>::from(0.5f64).and_then(|v: T| v.to_f64()) == Some(0.5) {
+        return 1.0;
+    }
+    T::max_value().to_f64().unwrap_or(f64::MAX)
+}
+
+fn channel_means<P>(img: &ImageBuffer<P, Vec<P::Subpixel>>) -> Vec<f64>
+where
+    P: Pixel + Sync + 'static,
+    P::Subpixel: Sync + 'static,
+{
+    let colors = color_channels::<P>();
+    let channels = P::CHANNEL_COUNT as usize;
+    let row = (img.width() as usize * channels).max(1);
+    let len = row * img.height() as usize;
+    // per-row partial sums keep every accumulator small and let rows reduce in parallel
+    let sums = img.as_raw()[..len]
+        .par_chunks(row)
+        .map(|r| {
+            // a fixed size accumulator stays in registers, no pixel type has more than four channels
+            let mut sums = [0f64; 4];
+            r.chunks_exact(channels).for_each(|p| {
+                sums.iter_mut()
+                    .zip(&p[..colors])
+                    .for_each(|(s, c)| *s += c.to_f64().unwrap_or(0.0))
+            });
+            sums[..colors].to_vec()
+        })
+        .reduce(
+            || vec![0f64; colors],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
+                a
+            },
+        );
+    let count = (img.width() as f64 * img.height() as f64).max(1.0);
+    sums.iter().map(|s| s / count).collect()
+}
+
+pub fn normalize_brightness<P>(
+    img1: &ImageBuffer<P, Vec<P::Subpixel>>,
+    img2: &ImageBuffer<P, Vec<P::Subpixel>>,
     percent: f64,
-) -> ImageBuffer<Luma<u16>, Vec<u16>> {
-    let (fbr, sbr): (u64, u64) = rayon::join(
-        || {
-            img1.pixels()
-                .map(|f| {
-                    let t: u64 = f.0[0].clone().into();
-                    t
-                })
-                .sum()
-        },
-        || {
-            img2.pixels()
-                .map(|f| {
-                    let t: u64 = f.0[0].clone().into();
-                    t
-                })
-                .sum()
-        },
-    );
-    let fbr = fbr / img1.len() as u64;
-    let sbr = sbr / img2.len() as u64;
-    let diff: f64 = sbr as f64 / fbr as f64;
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + Sync + 'static,
+    P::Subpixel: Send + Sync + 'static,
+{
+    let (mean1, mean2) = rayon::join(|| channel_means(img1), || channel_means(img2));
+    scale_to_means(img1, &mean1, &mean2, percent)
+}
+
+fn scale_to_means<P>(
+    img1: &ImageBuffer<P, Vec<P::Subpixel>>,
+    mean1: &[f64],
+    mean2: &[f64],
+    percent: f64,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + Sync + 'static,
+    P::Subpixel: Send + Sync + 'static,
+{
+    let diff: Vec<f64> = mean1
+        .iter()
+        .zip(mean2.iter())
+        .map(|(m1, m2)| if *m1 == 0.0 { 1.0 } else { m2 / m1 })
+        .collect();
     let mut res = img1.clone();
-    if (1f64 - diff).abs() < percent {
+    if diff.iter().all(|d| (1f64 - d).abs() < percent) {
         return res;
     }
-    res.pixels_mut()
-        .for_each(|f| f.0[0] = ((f.0[0] as f64) * diff) as u16);
+    let max = subpixel_max::<P::Subpixel>();
+    let row = (img1.width() * P::CHANNEL_COUNT as u32).max(1) as usize;
+    let channels = P::CHANNEL_COUNT as usize;
+    let scale = move |c: P::Subpixel, d: f64| -> P::Subpixel {
+        let value = (c.to_f64().unwrap_or(0.0) * d).clamp(0.0, max);
+        NumCast::from(value).unwrap_or(c)
+    };
+    if max > 1.0 && max <= u16::MAX as f64 {
+        // up to 16 bits every value can be looked up instead of converted through f64
+        let tables: Vec<Vec<P::Subpixel>> = diff
+            .iter()
+            .map(|d| {
+                (0..=max as usize)
+                    .map(|v| scale(NumCast::from(v).unwrap(), *d))
+                    .collect()
+            })
+            .collect();
+        res.par_chunks_mut(row).for_each(|r| {
+            r.chunks_exact_mut(channels).for_each(|f| {
+                f.iter_mut()
+                    .zip(tables.iter())
+                    .for_each(|(c, table)| *c = table[c.to_usize().unwrap_or(0)])
+            })
+        });
+        return res;
+    }
+    res.par_chunks_mut(row).for_each(|r| {
+        r.chunks_exact_mut(channels).for_each(|f| {
+            f.iter_mut()
+                .zip(diff.iter())
+                .for_each(|(c, d)| *c = scale(*c, *d))
+        })
+    });
     res
 }
 
+pub fn normalize_brightness_luma16(
+    img1: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    img2: &ImageBuffer<Luma<u16>, Vec<u16>>,
+    percent: f64,
+) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+    normalize_brightness(img1, img2, percent)
+}
+
 pub fn normalize_brightness_rgb16(
     img1: &ImageBuffer<Rgb<u16>, Vec<u16>>,
     img2: &ImageBuffer<Rgb<u16>, Vec<u16>>,
     percent: f64,
 ) -> ImageBuffer<Rgb<u16>, Vec<u16>> {
-    let mut rgb1 = [0f64, 0.0, 0.0];
-    let mut rgb2 = rgb1.clone();
-    rayon::join(
-        || {
-            img1.pixels().for_each(|f| {
-                rgb1[0] += f.0[0] as f64;
-                rgb1[1] += f.0[1] as f64;
-                rgb1[2] += f.0[2] as f64;
-            })
-        },
-        || {
-            img2.pixels().for_each(|f| {
-                rgb2[0] += f.0[0] as f64;
-                rgb2[1] += f.0[1] as f64;
-                rgb2[2] += f.0[2] as f64;
-            })
-        },
-    );
-    for (array, l) in vec![(&mut rgb1, img1.len()), (&mut rgb2, img2.len())] {
-        for i in 0..3 {
-            array[i] /= l as f64;
+    normalize_brightness(img1, img2, percent)
+}
+
+type RgbaBuffer = ImageBuffer<image::Rgba<u8>, Vec<u8>>;
+type MaskBuffer = ImageBuffer<Luma<u8>, Vec<u8>>;
+
+fn masked_pixels<'a>(
+    img: &'a RgbaBuffer,
+    mask: Option<&'a MaskBuffer>,
+) -> impl Iterator<Item = &'a image::Rgba<u8>> + 'a {
+    img.enumerate_pixels()
+        .filter(move |(x, y, _)| match mask {
+            Some(m) => *m.get_pixel(*x, *y) == MASK_TRUE,
+            None => true,
+        })
+        .map(|(_, _, p)| p)
+}
+
+fn masked_means(img: &RgbaBuffer, mask: &MaskBuffer) -> Option<Vec<f64>> {
+    let mut sums = [0f64; 3];
+    let mut count = 0f64;
+    masked_pixels(img, Some(mask)).for_each(|p| {
+        for (s, v) in sums.iter_mut().zip(p.0.iter()) {
+            *s += *v as f64;
         }
+        count += 1.0;
+    });
+    if count == 0.0 {
+        return None;
     }
-    let diff = [rgb2[0] / rgb1[0], rgb2[1] / rgb1[1], rgb2[2] / rgb1[2]];
-    let mut res = img1.clone();
-    if (1f64 - diff[0]).abs() < percent
-        && (1f64 - diff[1]).abs() < percent
-        && (1f64 - diff[2]).abs() < percent
-    {
-        return res;
+    Some(sums.iter().map(|s| s / count).collect())
+}
+
+pub fn normalize_brightness_masked(
+    img: &MaskImage,
+    reference: &MaskImage,
+    percent: f64,
+) -> RgbaBuffer {
+    match (
+        masked_means(&img.image, &img.mask),
+        masked_means(&reference.image, &reference.mask),
+    ) {
+        (Some(mean1), Some(mean2)) => scale_to_means(&img.image, &mean1, &mean2, percent),
+        _ => img.image.clone(),
+    }
+}
+
+fn cumulative_histograms(img: &RgbaBuffer, mask: Option<&MaskBuffer>) -> [[f64; 256]; 3] {
+    let mut histograms = [[0f64; 256]; 3];
+    let mut count = 0f64;
+    masked_pixels(img, mask).for_each(|p| {
+        for (histogram, v) in histograms.iter_mut().zip(p.0.iter()) {
+            histogram[*v as usize] += 1.0;
+        }
+        count += 1.0;
+    });
+    for histogram in histograms.iter_mut() {
+        let mut total = 0.0;
+        for bin in histogram.iter_mut() {
+            total += *bin;
+            *bin = total / count.max(1.0);
+        }
+    }
+    histograms
+}
+
+fn match_histogram_inner(
+    img: &RgbaBuffer,
+    mask: Option<&MaskBuffer>,
+    reference: &RgbaBuffer,
+    reference_mask: Option<&MaskBuffer>,
+) -> RgbaBuffer {
+    let source = cumulative_histograms(img, mask);
+    let target = cumulative_histograms(reference, reference_mask);
+    let mut tables = [[0u8; 256]; 3];
+    for ((table, source), target) in tables.iter_mut().zip(source.iter()).zip(target.iter()) {
+        for (v, entry) in table.iter_mut().enumerate() {
+            *entry = target.iter().position(|t| *t >= source[v]).unwrap_or(255) as u8;
+        }
     }
-    res.pixels_mut().for_each(|f| {
+    let mut res = img.clone();
+    res.pixels_mut().for_each(|p| {
+        for (c, table) in p.0.iter_mut().zip(tables.iter()) {
+            *c = table[*c as usize];
+        }
+    });
+    res
+}
+
+pub fn match_histogram(img: &RgbaBuffer, reference: &RgbaBuffer) -> RgbaBuffer {
+    match_histogram_inner(img, None, reference, None)
+}
+
+pub fn match_histogram_masked(img: &MaskImage, reference: &MaskImage) -> RgbaBuffer {
+    match_histogram_inner(
+        &img.image,
+        Some(&img.mask),
+        &reference.image,
+        Some(&reference.mask),
+    )
+}
+
+fn srgb_to_linear(v: f64) -> f64 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f64) -> f64 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+const WHITE_D65: [f64; 3] = [0.95047, 1.0, 1.08883];
+
+fn rgb_to_lab(rgb: [u8; 3]) -> [f64; 3] {
+    let [r, g, b] = rgb.map(|v| srgb_to_linear(v as f64 / 255.0));
+    let xyz = [
+        (0.4124 * r + 0.3576 * g + 0.1805 * b) / WHITE_D65[0],
+        (0.2126 * r + 0.7152 * g + 0.0722 * b) / WHITE_D65[1],
+        (0.0193 * r + 0.1192 * g + 0.9505 * b) / WHITE_D65[2],
+    ];
+    let [fx, fy, fz] = xyz.map(|t| {
+        if t > 216.0 / 24389.0 {
+            t.cbrt()
+        } else {
+            (24389.0 / 27.0 * t + 16.0) / 116.0
+        }
+    });
+    [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+fn lab_to_rgb(lab: [f64; 3]) -> [u8; 3] {
+    let fy = (lab[0] + 16.0) / 116.0;
+    let f = [fy + lab[1] / 500.0, fy, fy - lab[2] / 200.0];
+    let [x, y, z] = [0, 1, 2].map(|i| {
+        let t = if f[i].powi(3) > 216.0 / 24389.0 {
+            f[i].powi(3)
+        } else {
+            (116.0 * f[i] - 16.0) * 27.0 / 24389.0
+        };
+        t * WHITE_D65[i]
+    });
+    [
+        3.2406 * x - 1.5372 * y - 0.4986 * z,
+        -0.9689 * x + 1.8758 * y + 0.0415 * z,
+        0.0557 * x - 0.2040 * y + 1.0570 * z,
+    ]
+    .map(|v| (linear_to_srgb(v.clamp(0.0, 1.0)) * 255.0).round() as u8)
+}
+
+fn lab_statistics(img: &RgbaBuffer, mask: Option<&MaskBuffer>) -> ([f64; 3], [f64; 3]) {
+    let mut sum = [0f64; 3];
+    let mut squares = [0f64; 3];
+    let mut count = 0f64;
+    masked_pixels(img, mask).for_each(|p| {
+        let lab = rgb_to_lab([p.0[0], p.0[1], p.0[2]]);
+        for i in 0..3 {
+            sum[i] += lab[i];
+            squares[i] += lab[i] * lab[i];
+        }
+        count += 1.0;
+    });
+    let count = count.max(1.0);
+    let mean = sum.map(|s| s / count);
+    let mut deviation = [0f64; 3];
+    for i in 0..3 {
+        deviation[i] = (squares[i] / count - mean[i] * mean[i]).max(0.0).sqrt();
+    }
+    (mean, deviation)
+}
+
+fn color_transfer_inner(
+    img: &RgbaBuffer,
+    mask: Option<&MaskBuffer>,
+    reference: &RgbaBuffer,
+    reference_mask: Option<&MaskBuffer>,
+) -> RgbaBuffer {
+    let (source_mean, source_deviation) = lab_statistics(img, mask);
+    let (target_mean, target_deviation) = lab_statistics(reference, reference_mask);
+    let mut res = img.clone();
+    res.pixels_mut().for_each(|p| {
+        let mut lab = rgb_to_lab([p.0[0], p.0[1], p.0[2]]);
         for i in 0..3 {
-            f.0[i] = ((f.0[i] as f64) * diff[i]) as u16;
+            let scale = if source_deviation[i] > 0.0 {
+                target_deviation[i] / source_deviation[i]
+            } else {
+                1.0
+            };
+            lab[i] = (lab[i] - source_mean[i]) * scale + target_mean[i];
         }
+        let [r, g, b] = lab_to_rgb(lab);
+        p.0[..3].copy_from_slice(&[r, g, b]);
     });
     res
 }
+
+pub fn color_transfer(img: &RgbaBuffer, reference: &RgbaBuffer) -> RgbaBuffer {
+    color_transfer_inner(img, None, reference, None)
+}
+
+pub fn color_transfer_masked(img: &MaskImage, reference: &MaskImage) -> RgbaBuffer {
+    color_transfer_inner(
+        &img.image,
+        Some(&img.mask),
+        &reference.image,
+        Some(&reference.mask),
+    )
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhiteBalance {
+    GrayWorld,
+    WhitePatch(f64),
+}
+
+pub(crate) fn linear_table() -> [f64; 256] {
+    let mut table = [0f64; 256];
+    for (v, entry) in table.iter_mut().enumerate() {
+        *entry = srgb_to_linear(v as f64 / 255.0);
+    }
+    table
+}
+
+pub(crate) fn apply_linear_gain(p: &mut [u8], table: &[f64; 256], gains: [f64; 3]) {
+    for (c, gain) in p.iter_mut().zip(gains.iter()) {
+        let linear = (table[*c as usize] * gain).clamp(0.0, 1.0);
+        *c = (linear_to_srgb(linear) * 255.0).round() as u8;
+    }
+}
+
+fn apply_linear_gains(img: &RgbaBuffer, gains: [f64; 3]) -> RgbaBuffer {
+    let table = linear_table();
+    let mut res = img.clone();
+    res.par_chunks_mut(4)
+        .for_each(|p| apply_linear_gain(p, &table, gains));
+    res
+}
+
+pub(crate) fn white_balance_gains(
+    img: &RgbaBuffer,
+    mask: Option<&MaskBuffer>,
+    method: WhiteBalance,
+) -> [f64; 3] {
+    let table = linear_table();
+    match method {
+        WhiteBalance::GrayWorld => {
+            let mut sums = [0f64; 3];
+            masked_pixels(img, mask).for_each(|p| {
+                for (s, c) in sums.iter_mut().zip(p.0.iter()) {
+                    *s += table[*c as usize];
+                }
+            });
+            let gray = sums.iter().sum::<f64>() / 3.0;
+            sums.map(|s| if s > 0.0 { gray / s } else { 1.0 })
+        }
+        WhiteBalance::WhitePatch(percent) => {
+            let mut stats = vec![ChannelStats::default(); 3];
+            masked_pixels(img, mask).for_each(|p| {
+                stats
+                    .iter_mut()
+                    .zip(p.0.iter())
+                    .for_each(|(s, c)| s.add(*c))
+            });
+            let mut gains = [1f64; 3];
+            for (gain, s) in gains.iter_mut().zip(stats.iter()) {
+                if let Some(white) = s.percentile(percent).filter(|w| *w > 0) {
+                    *gain = 1.0 / table[white as usize];
+                }
+            }
+            gains
+        }
+    }
+}
+
+pub fn white_balance(img: &RgbaBuffer, method: WhiteBalance) -> RgbaBuffer {
+    apply_linear_gains(img, white_balance_gains(img, None, method))
+}
+
+pub fn white_balance_masked(img: &MaskImage, method: WhiteBalance) -> RgbaBuffer {
+    let gains = white_balance_gains(&img.image, Some(&img.mask), method);
+    apply_linear_gains(&img.image, gains)
+}
+
+pub(crate) fn temperature_gains(temperature: f64, tint: f64) -> [f64; 3] {
+    // positive temperature warms towards red, positive tint shifts towards magenta
+    let (temperature, tint) = (temperature.clamp(-1.0, 1.0), tint.clamp(-1.0, 1.0));
+    [
+        1.0 + 0.3 * temperature,
+        1.0 - 0.3 * tint,
+        1.0 - 0.3 * temperature,
+    ]
+}
+
+pub fn adjust_temperature(img: &RgbaBuffer, temperature: f64, tint: f64) -> RgbaBuffer {
+    apply_linear_gains(img, temperature_gains(temperature, tint))
+}
+
+pub(crate) fn exposure_gains(stops: f64) -> [f64; 3] {
+    [2f64.powf(stops); 3]
+}
+
+pub fn adjust_exposure(img: &RgbaBuffer, stops: f64) -> RgbaBuffer {
+    apply_linear_gains(img, exposure_gains(stops))
+}
@@ -0,0 +1,354 @@
+use crate::depth_image::DepthImage;
+use crate::mask_image::{BlendMode, MaskImage};
+use crate::operations::WhiteBalance;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SelectionMode {
+    #[default]
+    Replace,
+    And,
+    Or,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Edit {
+    Slice {
+        from: Option<u8>,
+        to: Option<u8>,
+        #[serde(default)]
+        mode: SelectionMode,
+    },
+    SelectForeground {
+        #[serde(default)]
+        mode: SelectionMode,
+    },
+    SelectBackground {
+        #[serde(default)]
+        mode: SelectionMode,
+    },
+    SelectZone {
+        index: usize,
+        zones: u8,
+        #[serde(default)]
+        mode: SelectionMode,
+    },
+    InvertDepth,
+    MaskNot,
+    MaskReset,
+    MaskErode {
+        radius: u32,
+    },
+    MaskDilate {
+        radius: u32,
+    },
+    MaskFeather {
+        radius: f32,
+    },
+    Brightness {
+        value: i32,
+    },
+    Contrast {
+        value: i32,
+    },
+    Sharpness {
+        value: i32,
+    },
+    Blur {
+        value: i32,
+    },
+    Hue {
+        degrees: f32,
+    },
+    Saturation {
+        amount: f32,
+    },
+    Vibrance {
+        amount: f32,
+    },
+    Desaturate,
+    Sepia,
+    Levels {
+        black: u8,
+        white: u8,
+        gamma: f32,
+    },
+    Curve {
+        points: Vec<(u8, u8)>,
+    },
+    WhiteBalance {
+        method: WhiteBalance,
+    },
+    Temperature {
+        temperature: f64,
+        tint: f64,
+    },
+    Exposure {
+        stops: f64,
+    },
+    Composite {
+        path: String,
+        x: u32,
+        y: u32,
+        blend_mode: BlendMode,
+    },
+    ApplyMask,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct EditPipeline {
+    edits: Vec<Edit>,
+    #[serde(skip)]
+    undone: Vec<Edit>,
+}
+
+// selections only read depth and contribute their mask, so edited pixels never need syncing back
+fn select(current: &mut MaskImage, selection: MaskImage, mode: SelectionMode) {
+    match mode {
+        SelectionMode::Replace => current.mask_copy(&selection),
+        SelectionMode::And => current.mask_and(&selection),
+        SelectionMode::Or => current.mask_or(&selection),
+    }
+}
+
+fn invalid_data<E: std::fmt::Display>(e: E) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())
+}
+
+impl EditPipeline {
+    pub fn new() -> Self {
+        EditPipeline::default()
+    }
+
+    pub fn edits(&self) -> &[Edit] {
+        &self.edits
+    }
+
+    pub fn push(&mut self, edit: Edit) {
+        self.edits.push(edit);
+        self.undone.clear();
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Edit> {
+        if index >= self.edits.len() {
+            return None;
+        }
+        self.undone.clear();
+        Some(self.edits.remove(index))
+    }
+
+    pub fn move_edit(&mut self, from: usize, to: usize) {
+        if from >= self.edits.len() || to >= self.edits.len() {
+            return;
+        }
+        let edit = self.edits.remove(from);
+        self.edits.insert(to, edit);
+        self.undone.clear();
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.edits.pop() {
+            Some(edit) => {
+                self.undone.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.undone.pop() {
+            Some(edit) => {
+                self.edits.push(edit);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn apply(&self, img: &DepthImage) -> Result<MaskImage, std::io::Error> {
+        let mut depth = img.clone();
+        let mut current = MaskImage::from_image(depth.image());
+        for edit in &self.edits {
+            match edit {
+                Edit::Slice { from, to, mode } => {
+                    select(&mut current, depth.slice(*from, *to), *mode)
+                }
+                Edit::SelectForeground { mode } => {
+                    select(&mut current, depth.select_foreground(), *mode)
+                }
+                Edit::SelectBackground { mode } => {
+                    select(&mut current, depth.select_background(), *mode)
+                }
+                Edit::SelectZone { index, zones, mode } => {
+                    match depth.select_zone(*index, *zones) {
+                        Some(zone) => select(&mut current, zone, *mode),
+                        None => {
+                            return Err(std::io::Error::new(
+                                std::io::ErrorKind::InvalidInput,
+                                "Zone doesn't exist",
+                            ))
+                        }
+                    }
+                }
+                Edit::InvertDepth => depth.invert_depth(),
+                Edit::MaskNot => current.mask_not(),
+                Edit::MaskReset => current.mask_reset(),
+                Edit::MaskErode { radius } => current.mask_erode(*radius),
+                Edit::MaskDilate { radius } => current.mask_dilate(*radius),
+                Edit::MaskFeather { radius } => current.mask_feather(*radius),
+                Edit::Brightness { value } => current.image_brightness(*value),
+                Edit::Contrast { value } => current.image_contrast(*value),
+                Edit::Sharpness { value } => current.image_sharpness(*value),
+                Edit::Blur { value } => current.image_blur(*value),
+                Edit::Hue { degrees } => current.image_hue(*degrees),
+                Edit::Saturation { amount } => current.image_saturation(*amount),
+                Edit::Vibrance { amount } => current.image_vibrance(*amount),
+                Edit::Desaturate => current.image_desaturate(),
+                Edit::Sepia => current.image_sepia(),
+                Edit::Levels {
+                    black,
+                    white,
+                    gamma,
+                } => current.image_levels(*black, *white, *gamma),
+                Edit::Curve { points } => current.image_curve(points),
+                Edit::WhiteBalance { method } => current.image_white_balance(*method),
+                Edit::Temperature { temperature, tint } => {
+                    current.image_temperature(*temperature, *tint)
+                }
+                Edit::Exposure { stops } => current.image_exposure(*stops),
+                Edit::Composite {
+                    path,
+                    x,
+                    y,
+                    blend_mode,
+                } => {
+                    let other = image::open(path).map_err(|_| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            "Failed to open image",
+                        )
+                    })?;
+                    current.composite(
+                        &MaskImage::from_image(other),
+                        disage::Position::new(*x, *y),
+                        *blend_mode,
+                    );
+                }
+                Edit::ApplyMask => current.apply_mask(),
+            }
+        }
+        Ok(current)
+    }
+
+    pub fn to_json(&self) -> Result<String, std::io::Error> {
+        serde_json::to_string_pretty(self).map_err(invalid_data)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, std::io::Error> {
+        serde_json::from_str(json).map_err(invalid_data)
+    }
+
+    pub fn to_toml(&self) -> Result<String, std::io::Error> {
+        toml::to_string(self).map_err(invalid_data)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, std::io::Error> {
+        toml::from_str(text).map_err(invalid_data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pipeline() -> EditPipeline {
+        let mut pipeline = EditPipeline::new();
+        pipeline.push(Edit::Slice {
+            from: None,
+            to: Some(200),
+            mode: SelectionMode::Replace,
+        });
+        pipeline.push(Edit::SelectZone {
+            index: 1,
+            zones: 3,
+            mode: SelectionMode::Or,
+        });
+        pipeline.push(Edit::WhiteBalance {
+            method: WhiteBalance::GrayWorld,
+        });
+        pipeline.push(Edit::WhiteBalance {
+            method: WhiteBalance::WhitePatch(0.98),
+        });
+        pipeline.push(Edit::Curve {
+            points: vec![(64, 48), (192, 208)],
+        });
+        pipeline.push(Edit::ApplyMask);
+        pipeline
+    }
+
+    #[test]
+    fn round_trip() {
+        let pipeline = pipeline();
+        let json = pipeline.to_json().unwrap();
+        assert!(json.contains(r#""op": "slice""#));
+        assert!(json.contains(r#""method": "gray_world""#));
+        assert!(json.contains(r#""white_patch": 0.98"#));
+        assert_eq!(EditPipeline::from_json(&json).unwrap(), pipeline);
+        let toml = pipeline.to_toml().unwrap();
+        assert!(toml.contains(r#"op = "select_zone""#));
+        assert!(!toml.contains("from"));
+        assert_eq!(EditPipeline::from_toml(&toml).unwrap(), pipeline);
+        let text = r#"{"edits": [{"op": "slice", "to": 80}, {"op": "mask_not"}]}"#;
+        assert_eq!(
+            EditPipeline::from_json(text).unwrap().edits(),
+            &[
+                Edit::Slice {
+                    from: None,
+                    to: Some(80),
+                    mode: SelectionMode::Replace,
+                },
+                Edit::MaskNot,
+            ]
+        );
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut pipeline = pipeline();
+        let edits = pipeline.edits().to_vec();
+        assert!(pipeline.undo());
+        assert!(pipeline.undo());
+        assert_eq!(pipeline.edits(), &edits[..4]);
+        assert!(pipeline.redo());
+        assert_eq!(pipeline.edits(), &edits[..5]);
+        // a new edit drops whatever was left to redo
+        pipeline.push(Edit::MaskNot);
+        assert!(!pipeline.redo());
+        assert_eq!(pipeline.edits().last(), Some(&Edit::MaskNot));
+        while pipeline.undo() {}
+        assert!(pipeline.edits().is_empty());
+        assert!(pipeline.redo());
+        assert_eq!(pipeline.edits(), &edits[..1]);
+    }
+
+    #[test]
+    fn move_edit() {
+        let mut pipeline = pipeline();
+        let edits = pipeline.edits().to_vec();
+        pipeline.move_edit(0, 2);
+        assert_eq!(
+            pipeline.edits(),
+            &[&edits[1..3], &edits[..1], &edits[3..]].concat()[..]
+        );
+        pipeline.move_edit(2, 0);
+        assert_eq!(pipeline.edits(), &edits[..]);
+        pipeline.move_edit(0, edits.len());
+        assert_eq!(pipeline.edits(), &edits[..]);
+        assert!(pipeline.undo());
+        pipeline.move_edit(1, 0);
+        assert!(!pipeline.redo());
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+pub trait ProgressReporter: Sync {
+    fn report(&self, done: u64, total: u64);
+}
+
+impl<F: Fn(u64, u64) + Sync> ProgressReporter for F {
+    fn report(&self, done: u64, total: u64) {
+        self(done, total)
+    }
+}
+
+#[cfg(feature = "indicatif")]
+impl ProgressReporter for indicatif::ProgressBar {
+    fn report(&self, done: u64, total: u64) {
+        self.set_length(total);
+        self.set_position(done);
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Progress<'a> {
+    pub reporter: Option<&'a dyn ProgressReporter>,
+    pub cancel: Option<&'a CancellationToken>,
+}
+
+impl<'a> Progress<'a> {
+    pub fn report(&self, done: u64, total: u64) {
+        if let Some(reporter) = self.reporter {
+            reporter.report(done, total);
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        match self.cancel {
+            Some(cancel) => cancel.is_cancelled(),
+            None => false,
+        }
+    }
+
+    pub fn check(&self) -> Result<(), std::io::Error> {
+        if self.is_cancelled() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Interrupted,
+                "Operation cancelled",
+            ));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+use crate::depth_image::DepthImage;
+use crate::mask_image::{MaskImage, MASK_TRUE};
+use image::DynamicImage;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelStats {
+    pub histogram: [u64; 256],
+}
+
+impl Default for ChannelStats {
+    fn default() -> Self {
+        ChannelStats {
+            histogram: [0; 256],
+        }
+    }
+}
+
+impl ChannelStats {
+    pub fn add(&mut self, value: u8) {
+        self.histogram[value as usize] += 1;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.histogram.iter().sum()
+    }
+
+    pub fn min(&self) -> Option<u8> {
+        self.histogram.iter().position(|c| *c > 0).map(|v| v as u8)
+    }
+
+    pub fn max(&self) -> Option<u8> {
+        self.histogram.iter().rposition(|c| *c > 0).map(|v| v as u8)
+    }
+
+    pub fn mean(&self) -> Option<f64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let sum: f64 = self
+            .histogram
+            .iter()
+            .enumerate()
+            .map(|(v, c)| v as f64 * *c as f64)
+            .sum();
+        Some(sum / count as f64)
+    }
+
+    pub fn std_dev(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let variance: f64 = self
+            .histogram
+            .iter()
+            .enumerate()
+            .map(|(v, c)| (v as f64 - mean).powi(2) * *c as f64)
+            .sum::<f64>()
+            / self.count() as f64;
+        Some(variance.sqrt())
+    }
+
+    pub fn percentile(&self, percent: f64) -> Option<u8> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((percent.clamp(0.0, 100.0) / 100.0 * count as f64).ceil() as u64).max(1);
+        let mut total = 0;
+        for (v, c) in self.histogram.iter().enumerate() {
+            total += c;
+            if total >= rank {
+                return Some(v as u8);
+            }
+        }
+        self.max()
+    }
+
+    pub fn median(&self) -> Option<u8> {
+        self.percentile(50.0)
+    }
+}
+
+impl MaskImage {
+    pub fn stats(&self) -> Vec<ChannelStats> {
+        let mut res = vec![ChannelStats::default(); 3];
+        self.image
+            .pixels()
+            .zip(self.mask.pixels())
+            .filter(|(_, m)| **m == MASK_TRUE)
+            .for_each(|(p, _)| res.iter_mut().zip(p.0.iter()).for_each(|(s, v)| s.add(*v)));
+        res
+    }
+}
+
+impl DepthImage {
+    pub fn depth_stats(&self, mask: &MaskImage) -> ChannelStats {
+        let mask = if mask.dimensions() != self.dimensions() {
+            DynamicImage::ImageLuma8(mask.mask.clone())
+                .resize_exact(self.width(), self.height(), image::imageops::Gaussian)
+                .to_luma8()
+        } else {
+            mask.mask.clone()
+        };
+        let mut res = ChannelStats::default();
+        self.depth
+            .pixels()
+            .zip(mask.pixels())
+            .filter(|(_, m)| **m == MASK_TRUE)
+            .for_each(|(d, _)| res.add(d.0[0]));
+        res
+    }
+}
@@ -0,0 +1,324 @@
+use crate::depth_image::DepthImage;
+use image::codecs::gif;
+use image::{imageops, Delay, DynamicImage, Frame, ImageBuffer, Rgba};
+
+type WarpedPixel = Option<(u8, Rgba<u8>)>;
+
+fn fill_line(warped: &mut [WarpedPixel], indices: &[usize]) {
+    let mut left = Vec::with_capacity(indices.len());
+    let mut last = None;
+    for index in indices {
+        if warped[*index].is_some() {
+            last = warped[*index];
+        }
+        left.push(last);
+    }
+    let mut next: WarpedPixel = None;
+    for (i, index) in indices.iter().enumerate().rev() {
+        if warped[*index].is_some() {
+            next = warped[*index];
+            continue;
+        }
+        // disocclusions reveal what was behind, so prefer the farther neighbour
+        warped[*index] = match (left[i], next) {
+            (Some(l), Some(r)) => Some(if l.0 >= r.0 { l } else { r }),
+            (l, r) => l.or(r),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StereoLayout {
+    SideBySide,
+    OverUnder,
+}
+
+impl DepthImage {
+    fn warp<F: Fn(f32, f32, u8) -> (f32, f32)>(&self, target_of: F) -> DynamicImage {
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let mut warped: Vec<WarpedPixel> = vec![None; width * height];
+        for (x, y, p) in self.image.enumerate_pixels() {
+            let d = self.depth.get_pixel(x, y).0[0];
+            let (tx, ty) = target_of(x as f32, y as f32, d);
+            let (tx, ty) = (tx.round(), ty.round());
+            if tx < 0.0 || ty < 0.0 || tx >= width as f32 || ty >= height as f32 {
+                continue;
+            }
+            let target = &mut warped[ty as usize * width + tx as usize];
+            match target {
+                Some((z, _)) if *z < d => {}
+                _ => *target = Some((d, *p)),
+            }
+        }
+        for y in 0..height {
+            let indices: Vec<usize> = (0..width).map(|x| y * width + x).collect();
+            fill_line(&mut warped, &indices);
+        }
+        for x in 0..width {
+            let indices: Vec<usize> = (0..height).map(|y| y * width + x).collect();
+            fill_line(&mut warped, &indices);
+        }
+        let res = ImageBuffer::from_fn(width as u32, height as u32, |x, y| {
+            warped[y as usize * width + x as usize].map_or(Rgba([0; 4]), |(_, p)| p)
+        });
+        DynamicImage::ImageRgba8(res)
+    }
+
+    pub fn render_view(&self, shift: (f32, f32), zoom: f32) -> DynamicImage {
+        let (cx, cy) = (self.width() as f32 / 2.0, self.height() as f32 / 2.0);
+        self.warp(|x, y, d| {
+            let nearness = (u8::MAX - d) as f32 / 255.0;
+            let scale = 1.0 + zoom * nearness;
+            (
+                cx + (x - cx) * scale + shift.0 * nearness,
+                cy + (y - cy) * scale + shift.1 * nearness,
+            )
+        })
+    }
+
+    pub fn right_view(&self, baseline: f32, convergence: u8) -> DynamicImage {
+        self.warp(|x, y, d| {
+            let disparity = baseline * (convergence as f32 - d as f32) / 255.0;
+            (x - disparity, y)
+        })
+    }
+
+    pub fn anaglyph(&self, baseline: f32, convergence: u8) -> DynamicImage {
+        let mut res = self.right_view(baseline, convergence).to_rgba8();
+        res.pixels_mut()
+            .zip(self.image.pixels())
+            .for_each(|(right, left)| right.0[0] = left.0[0]);
+        DynamicImage::ImageRgba8(res)
+    }
+
+    pub fn stereo_pair(
+        &self,
+        baseline: f32,
+        convergence: u8,
+        layout: StereoLayout,
+    ) -> DynamicImage {
+        let right = self.right_view(baseline, convergence).to_rgba8();
+        let (width, height) = (self.width(), self.height());
+        let (offset_x, offset_y) = match layout {
+            StereoLayout::SideBySide => (width, 0),
+            StereoLayout::OverUnder => (0, height),
+        };
+        let mut res = ImageBuffer::new(width + offset_x, height + offset_y);
+        imageops::replace(&mut res, &self.image, 0, 0);
+        imageops::replace(&mut res, &right, offset_x, offset_y);
+        DynamicImage::ImageRgba8(res)
+    }
+
+    pub fn parallax_frames(&self, amplitude: f32, zoom: f32, frames: u32) -> Vec<DynamicImage> {
+        (0..frames)
+            .map(|i| {
+                let t = 2.0 * std::f32::consts::PI * i as f32 / frames as f32;
+                let shift = (amplitude * t.cos(), amplitude * t.sin() / 2.0);
+                self.render_view(shift, zoom * (1.0 - t.cos()) / 2.0)
+            })
+            .collect()
+    }
+
+    pub fn autostereogram(
+        &self,
+        pattern: Option<DynamicImage>,
+        eye_separation: u32,
+        depth_factor: f32,
+    ) -> Result<DynamicImage, std::io::Error> {
+        let pattern = pattern.map(|p| p.to_rgba8());
+        if let Some(p) = &pattern {
+            if p.width() == 0 || p.height() == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Pattern is empty",
+                ));
+            }
+        }
+        let (width, height) = (self.width() as usize, self.height() as usize);
+        let mut seed = 0x2545_f491_u32;
+        let mut random = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        let mut res = ImageBuffer::new(width as u32, height as u32);
+        for y in 0..height {
+            let z: Vec<f32> = (0..width)
+                .map(|x| (u8::MAX - self.depth.get_pixel(x as u32, y as u32).0[0]) as f32 / 255.0)
+                .collect();
+            let mut same: Vec<usize> = (0..width).collect();
+            for (left, right) in stereo_constraints(&z, y, eye_separation, depth_factor) {
+                link(&mut same, left, right);
+            }
+            for x in (0..width).rev() {
+                let pixel = if same[x] == x {
+                    match &pattern {
+                        Some(p) => *p.get_pixel(x as u32 % p.width(), y as u32 % p.height()),
+                        None => {
+                            let [r, g, b, _] = random().to_le_bytes();
+                            Rgba([r, g, b, u8::MAX])
+                        }
+                    }
+                } else {
+                    *res.get_pixel(same[x] as u32, y as u32)
+                };
+                res.put_pixel(x as u32, y as u32, pixel);
+            }
+        }
+        Ok(DynamicImage::ImageRgba8(res))
+    }
+}
+
+// keeps every pixel pointing at a pixel to its right that must share its color
+fn link(same: &mut [usize], mut left: usize, mut right: usize) {
+    let mut l = same[left];
+    while l != left && l != right {
+        if l < right {
+            left = l;
+        } else {
+            same[left] = right;
+            left = right;
+            right = l;
+        }
+        l = same[left];
+    }
+    same[left] = right;
+}
+
+fn stereo_constraints(
+    z: &[f32],
+    y: usize,
+    eye_separation: u32,
+    depth_factor: f32,
+) -> Vec<(usize, usize)> {
+    let width = z.len();
+    let eye = eye_separation as f32;
+    let mu = depth_factor;
+    let separation = |z: f32| ((1.0 - mu * z) * eye / (2.0 - mu * z)).round() as usize;
+    let mut res = Vec::new();
+    for x in 0..width {
+        let s = separation(z[x]);
+        let left = x as i64 - ((s + (s & y & 1)) / 2) as i64;
+        let right = left + s as i64;
+        if left < 0 || right >= width as i64 {
+            continue;
+        }
+        // a point is hidden if something between the eyes' rays is nearer
+        let mut visible = true;
+        let mut t = 1;
+        loop {
+            let zt = z[x] + 2.0 * (2.0 - mu * z[x]) * t as f32 / (mu * eye);
+            if zt >= 1.0 || x < t || x + t >= width {
+                break;
+            }
+            visible = z[x - t] < zt && z[x + t] < zt;
+            if !visible {
+                break;
+            }
+            t += 1;
+        }
+        if visible {
+            res.push((left as usize, right as usize));
+        }
+    }
+    res
+}
+
+pub fn save_gif(
+    frames: &[DynamicImage],
+    path: &str,
+    delay_ms: u32,
+) -> Result<(), image::ImageError> {
+    let mut encoder = gif::GifEncoder::new(std::fs::File::create(path)?);
+    encoder.set_repeat(gif::Repeat::Infinite)?;
+    encoder.encode_frames(
+        frames.iter().map(|f| {
+            Frame::from_parts(f.to_rgba8(), 0, 0, Delay::from_numer_denom_ms(delay_ms, 1))
+        }),
+    )
+}
+
+pub fn save_png_sequence(frames: &[DynamicImage], prefix: &str) -> Result<(), image::ImageError> {
+    for (i, frame) in frames.iter().enumerate() {
+        frame.save(format!("{}_{:04}.png", prefix, i))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::depth_image::test_depth_image;
+
+    fn assert_constraints_hold(img: &DepthImage, pattern: Option<DynamicImage>) {
+        let (eye, mu) = (40, 1.0 / 3.0);
+        let res = img.autostereogram(pattern, eye, mu).unwrap().to_rgba8();
+        for y in 0..img.height() {
+            let z: Vec<f32> = (0..img.width())
+                .map(|x| (u8::MAX - img.depth.get_pixel(x, y).0[0]) as f32 / 255.0)
+                .collect();
+            for (left, right) in stereo_constraints(&z, y as usize, eye, mu) {
+                assert_eq!(
+                    res.get_pixel(left as u32, y),
+                    res.get_pixel(right as u32, y),
+                    "({}, {}) and ({}, {})",
+                    left,
+                    y,
+                    right,
+                    y
+                );
+            }
+        }
+    }
+
+    fn depth_image(depth: impl Fn(u32, u32) -> u8) -> DepthImage {
+        test_depth_image(160, 24, depth)
+    }
+
+    #[test]
+    fn autostereogram_stepped_depth() {
+        let img = depth_image(|x, _| (x / 32 * 60) as u8);
+        assert_constraints_hold(&img, None);
+        let pattern = DynamicImage::ImageRgba8(ImageBuffer::from_fn(7, 5, |x, y| {
+            Rgba([(x * 30) as u8, (y * 50) as u8, 0, u8::MAX])
+        }));
+        assert_constraints_hold(&img, Some(pattern));
+    }
+
+    #[test]
+    fn autostereogram_noisy_depth() {
+        let img = depth_image(|x, y| ((x * 7919 + y * 104729) % 251) as u8);
+        assert_constraints_hold(&img, None);
+    }
+
+    #[test]
+    fn link_keeps_every_constraint() {
+        let width = 200;
+        let mut same: Vec<usize> = (0..width).collect();
+        let mut constraints = Vec::new();
+        // separations vary like on noisy depth, so links from later pixels cross earlier ones
+        for x in 20..width - 20 {
+            let s = 10 + (x * x * 31 + x * 7) % 21;
+            let (left, right) = (x - s / 2, x - s / 2 + s);
+            constraints.push((left, right));
+            link(&mut same, left, right);
+        }
+        let root = |mut x: usize| {
+            while same[x] != x {
+                x = same[x];
+            }
+            x
+        };
+        for (left, right) in constraints {
+            assert_eq!(root(left), root(right), "{} and {}", left, right);
+        }
+    }
+
+    #[test]
+    fn autostereogram_empty_pattern() {
+        let img = depth_image(|_, _| 0);
+        let pattern = DynamicImage::new_rgba8(0, 0);
+        assert!(img.autostereogram(Some(pattern), 40, 0.3).is_err());
+    }
+}